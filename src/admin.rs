@@ -0,0 +1,76 @@
+use actix_web::{get, http::StatusCode, post, web, HttpRequest, HttpResponse, ResponseError};
+use parking_lot::RwLock;
+
+use crate::{
+    jsml_error::JsmlError,
+    logger::{LogEntry, RouteEntry},
+    state::State,
+};
+
+/// Brings back the database as it was loaded, writing it to the source
+#[post("/reset")]
+async fn reset(
+    req: HttpRequest,
+    data: web::Data<RwLock<State>>,
+) -> Result<HttpResponse, JsmlError> {
+    let mut data = data.write();
+    let mut log = RouteEntry::new(&format!("POST - localhost:{}/__admin/reset", data.port));
+    match data.reset() {
+        Ok(()) => {
+            log.update(StatusCode::NO_CONTENT);
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Err(e) => {
+            log.update(e.status_code());
+            Err(e.at(req.path()))
+        }
+    }
+}
+
+/// Keeps a copy of the current database under `name`, replacing any previous one
+#[post("/snapshot/{name}")]
+async fn snapshot(path: web::Path<String>, data: web::Data<RwLock<State>>) -> HttpResponse {
+    let name = path.into_inner();
+    let mut data = data.write();
+    let mut log = RouteEntry::new(&format!(
+        "POST - localhost:{}/__admin/snapshot/{name}",
+        data.port
+    ));
+    data.snapshot(&name);
+    log.update(StatusCode::NO_CONTENT);
+    HttpResponse::NoContent().finish()
+}
+
+/// Brings back the database saved under `name`, writing it to the source
+#[post("/restore/{name}")]
+async fn restore(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<RwLock<State>>,
+) -> Result<HttpResponse, JsmlError> {
+    let name = path.into_inner();
+    let mut data = data.write();
+    let mut log = RouteEntry::new(&format!(
+        "POST - localhost:{}/__admin/restore/{name}",
+        data.port
+    ));
+    match data.restore(&name) {
+        Ok(()) => {
+            log.update(StatusCode::NO_CONTENT);
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Err(e) => {
+            log.update(e.status_code());
+            Err(e.at(req.path()))
+        }
+    }
+}
+
+/// Dumps the whole database as it would be written to the source
+#[get("/db")]
+async fn db(data: web::Data<RwLock<State>>) -> HttpResponse {
+    let data = data.read();
+    let mut log = RouteEntry::new(&format!("GET - localhost:{}/__admin/db", data.port));
+    log.update(StatusCode::OK);
+    HttpResponse::Ok().json(data.serialize_all())
+}
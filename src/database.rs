@@ -1,195 +1,993 @@
-use itertools::Itertools;
-use serde_json::{json, Value};
-use std::collections::HashMap;
-use uuid::Uuid;
+use clap::ValueEnum;
+use indexmap::IndexMap;
+use serde_json::{json, Map, Value};
+use std::{cmp::Ordering, collections::HashSet};
 
-use crate::{jsml_error::JsmlError, routes::QueryParams};
+use crate::{
+    jsml_error::JsmlError,
+    random::{self, Random},
+    routes::{Filter, Operator, Order, QueryParams, Sort},
+    validation::{Schemas, META_SCHEMA_KEY, SCHEMA_KEY},
+    Args,
+};
 
-#[derive(Debug)]
-pub struct Database {
+const NULL_RANK: u8 = 4;
+
+/// A slice of a filtered and sorted collection, along with the number of matches
+pub struct Page<'a> {
+    pub items: Vec<&'a Value>,
+    pub total: usize,
+}
+
+/// How `post` generates the identifier of an item sent without one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IdStrategy {
+    /// Random v4 uuid strings
+    Uuid,
+    /// One more than the largest integer id of the collection
+    Increment,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
     pub id_key: String,
-    pub database: HashMap<String, HashMap<String, Value>>,
+    pub id_strategy: IdStrategy,
+    /// Give items loaded without an id one from `id_strategy` instead of failing
+    pub assign_ids: bool,
+    pub random: Random,
+    /// Appended to a singular collection name to name its foreign keys (`post` -> `postId`)
+    pub foreign_key_suffix: String,
+    /// Schemas of the collections read from a sidecar file, `null` when there is none
+    pub schemas: Value,
+}
+
+impl From<&Args> for Options {
+    fn from(args: &Args) -> Self {
+        Self {
+            id_key: args.id.clone(),
+            id_strategy: args.id_strategy,
+            assign_ids: args.generate,
+            random: Random::new(args.seed),
+            foreign_key_suffix: args.foreign_key_suffix.clone(),
+            schemas: Value::Null,
+        }
+    }
+}
+
+/// Top-level entry of the source: an array of items, or a single object such as `/profile`
+#[derive(Debug, Clone)]
+pub enum Resource {
+    Collection(IndexMap<String, Value>),
+    Singular(Value),
+}
+
+/// Prefix of the routes served by the server itself, such as `/__admin`
+pub const RESERVED_PREFIX: &str = "__";
+
+/// Top-level entries of the source written back as they were read rather than served
+pub const KEPT_KEYS: [&str; 2] = [SCHEMA_KEY, META_SCHEMA_KEY];
+
+#[derive(Debug, Clone)]
+pub struct Database {
+    pub options: Options,
+    pub database: IndexMap<String, Resource>,
+    pub schemas: Schemas,
+    /// Entries of `KEPT_KEYS` found in the source, with their position among its entries
+    kept: Vec<(usize, String, Value)>,
 }
 
 impl Database {
-    pub fn new(id_key: &str, data: &Value) -> Result<Self, JsmlError> {
-        let mut database = HashMap::new();
+    pub fn new(options: &Options, data: &Value) -> Result<Self, JsmlError> {
+        let id_key = &options.id_key;
+        let mut database = IndexMap::new();
         let Some(data) = data.as_object() else {
-            return Err(JsmlError::new("Error: invalid file content"));
+            return Err(JsmlError::bad_request("invalid file content"));
         };
+        let schemas = Schemas::new(&options.schemas, data.get(SCHEMA_KEY))?;
+        let mut kept = vec![];
 
-        for (key, value) in data {
+        for (i, (key, value)) in data.iter().enumerate() {
+            if KEPT_KEYS.contains(&key.as_str()) {
+                kept.push((i, key.clone(), value.clone()));
+                continue;
+            }
+            if key.starts_with(RESERVED_PREFIX) {
+                return Err(JsmlError::bad_request(&format!(
+                    "'{key}' is reserved: names starting with '{RESERVED_PREFIX}' are not served"
+                )));
+            }
+            if value.is_object() {
+                database.insert(key.to_string(), Resource::Singular(value.clone()));
+                continue;
+            }
             let Some(collection) = value.as_array() else {
-                return Err(JsmlError::new("Error: invalid file content"));
+                return Err(JsmlError::bad_request("invalid file content"));
             };
-            let mut col = HashMap::new();
+            let mut col = IndexMap::new();
+            // Ids found further on in the source, which generated ones must not reuse
+            let explicit: HashSet<String> = collection
+                .iter()
+                .filter_map(|item| Self::id_string(&item[id_key]))
+                .collect();
             for item in collection.iter() {
-                let Value::String(key) = &item[id_key] else {
-                return Err(JsmlError::new(
-                    &format!("No field named: '{id_key}'"),
-                ));
-            };
-                col.insert(String::from(key), item.clone());
+                if let Some(key) = Self::id_string(&item[id_key]) {
+                    col.insert(key, item.clone());
+                    continue;
+                }
+                let (true, Some(fields)) = (options.assign_ids, item.as_object()) else {
+                    return Err(JsmlError::bad_request(&format!(
+                        "No field named: '{id_key}'"
+                    )));
+                };
+                let id = Self::generate_id(options, &col, |id| {
+                    col.contains_key(id) || explicit.contains(id)
+                })?;
+                let mut item = Map::new();
+                item.insert(id_key.clone(), id.clone());
+                item.extend(fields.clone());
+                col.insert(
+                    Self::id_string(&id).unwrap_or_default(),
+                    Value::Object(item),
+                );
             }
-            database.insert(key.to_string(), col);
+            database.insert(key.to_string(), Resource::Collection(col));
         }
-        Ok(Self {
-            id_key: String::from(id_key),
+        let database = Self {
+            options: options.clone(),
             database,
-        })
+            schemas,
+            kept,
+        };
+        database.check_all()?;
+        Ok(database)
     }
 
-    pub fn query<'a>(
-        &'a self,
-        route: &str,
-        query: &QueryParams,
-    ) -> Result<Vec<&'a Value>, JsmlError> {
-        let Some(collection) = self.database.get(route) else {
-            return Err(JsmlError::new(&format!("collection {route} not found")));
-        };
-        let mut response: Vec<&Value> = vec![];
-        if let Some(page) = query.page {
-            let limit = match query.limit {
-                Some(limit) => limit,
-                None => 10,
-            };
-            for key in collection.keys().sorted().skip(limit * page).take(limit) {
-                if Self::match_query(&query, &collection[key]) {
-                    response.push(&collection[key]);
+    /// Validates every item against the schema of its collection, reporting all the violations
+    fn check_all(&self) -> Result<(), JsmlError> {
+        let mut violations = vec![];
+        for (route, resource) in &self.database {
+            match resource {
+                Resource::Collection(collection) => {
+                    for (i, item) in collection.values().enumerate() {
+                        let prefix = format!("/{route}/{i}");
+                        violations.extend(self.schemas.violations(route, item, &prefix));
+                    }
                 }
-            }
-        } else {
-            for key in collection.keys().sorted() {
-                if Self::match_query(&query, &collection[key]) {
-                    response.push(&collection[key]);
+                Resource::Singular(value) => {
+                    let prefix = format!("/{route}");
+                    violations.extend(self.schemas.violations(route, value, &prefix));
                 }
             }
         }
-        Ok(response)
+        if violations.is_empty() {
+            return Ok(());
+        }
+        let report: Vec<String> = violations
+            .iter()
+            .map(|violation| format!("  {}: {}", violation.path, violation.message))
+            .collect();
+        Err(JsmlError::unprocessable(
+            &format!(
+                "{} values do not match their schema:\n{}",
+                violations.len(),
+                report.join("\n")
+            ),
+            violations,
+        ))
+    }
+
+    pub fn query<'a>(&'a self, route: &str, query: &QueryParams) -> Result<Page<'a>, JsmlError> {
+        let collection = self.collection(route)?;
+        let mut items: Vec<&Value> = collection
+            .values()
+            .filter(|item| Self::match_query(query, item))
+            .collect();
+        Self::sort(&mut items, &query.sort);
+        let total = items.len();
+        if let Some(page) = query.page {
+            let limit = query.page_size();
+            items = items
+                .into_iter()
+                .skip(limit.saturating_mul(page))
+                .take(limit)
+                .collect();
+        } else if let Some(start) = query.start {
+            let end = match (query.end, query.limit) {
+                (Some(end), _) => end,
+                (None, Some(limit)) => start.saturating_add(limit),
+                (None, None) => total,
+            };
+            items = items
+                .into_iter()
+                .skip(start)
+                .take(end.saturating_sub(start))
+                .collect();
+        } else if let Some(end) = query.end {
+            items.truncate(end);
+        } else if let Some(limit) = query.limit {
+            items.truncate(limit);
+        }
+        Ok(Page { items, total })
+    }
+
+    /// Queries `child`, restricted to the items whose foreign key points at `parent/id`
+    pub fn query_children<'a>(
+        &'a self,
+        parent: &str,
+        id: &str,
+        child: &str,
+        query: &mut QueryParams,
+    ) -> Result<Page<'a>, JsmlError> {
+        self.get(parent, id)?;
+        query
+            .filters
+            .push(Filter::equals(&self.foreign_key(parent), id));
+        self.query(child, query)
     }
 
     pub fn get(&self, route: &str, id: &str) -> Result<&Value, JsmlError> {
-        let Some(collection) = self.database.get(route) else {
-            return Err(JsmlError::new(&format!("collection {route} not found")));
-        };
+        let collection = self.collection(route)?;
         let Some(response) = collection.get(id) else {
-            return Err(JsmlError::new(&format!("item {route}/{id} not found")));
+            return Err(JsmlError::not_found(&format!(
+                "item {route}/{id} not found"
+            )));
         };
         Ok(response)
     }
 
     pub fn delete(&mut self, route: &str, id: &str) -> Result<(), JsmlError> {
-        let Some(collection) = self.database.get_mut(route) else {
-            return Err(JsmlError::new(&format!("collection {route} not found")));
-        };
-        match collection.remove(id) {
-            None => Err(JsmlError::new(&format!("item {route}/{id} not found"))),
+        let collection = self.collection_mut(route)?;
+        match collection.shift_remove(id) {
+            None => Err(JsmlError::not_found(&format!(
+                "item {route}/{id} not found"
+            ))),
             _ => Ok(()),
         }
     }
 
     pub fn put(&mut self, route: &str, id: &str, body: &Value) -> Result<Value, JsmlError> {
-        let Some(col) = self.database.get_mut(route) else {
-            return Err(JsmlError::new(&format!("collection {route} not found")));
+        let id_key = &self.options.id_key;
+        let Some(Resource::Collection(col)) = self.database.get_mut(route) else {
+            return Err(Self::not_a_collection(route));
         };
         let Some(item) = col.get_mut(id) else {
-            return Err(JsmlError::new(&format!("item {route}/{id} not found")));
+            return Err(JsmlError::not_found(&format!(
+                "item {route}/{id} not found"
+            )));
         };
         let Some(body) = body.as_object() else {
-            return Err(JsmlError::new("invalid request body"));
+            return Err(JsmlError::bad_request("invalid request body"));
         };
 
-        item.as_object_mut();
-        let id = &item[&self.id_key].clone();
-        *item = json!(serde_json::Value::Null);
-        for (key, value) in body {
-            item[key] = value.clone();
+        let id = item[id_key].clone();
+        let position = item
+            .as_object()
+            .and_then(|item| item.keys().position(|key| key == id_key))
+            .unwrap_or(0);
+        // Keep the id where it was so a replaced item only changes its own fields on save
+        let mut replaced = Map::new();
+        for (i, (key, value)) in body.iter().filter(|(key, _)| *key != id_key).enumerate() {
+            if i == position {
+                replaced.insert(id_key.clone(), id.clone());
+            }
+            replaced.insert(key.clone(), value.clone());
+        }
+        if !replaced.contains_key(id_key) {
+            replaced.insert(id_key.clone(), id);
         }
-        item[&self.id_key] = id.clone();
+        let replaced = Value::Object(replaced);
+        self.schemas.check(route, &replaced)?;
+        *item = replaced;
         Ok(item.clone())
     }
 
     pub fn patch(&mut self, route: &str, id: &str, body: &Value) -> Result<Value, JsmlError> {
-        let Some(col) = self.database.get_mut(route) else {
-            return Err(JsmlError::new(&format!("collection {route} not found")));
+        let Some(Resource::Collection(col)) = self.database.get_mut(route) else {
+            return Err(Self::not_a_collection(route));
         };
         let Some(item) = col.get_mut(id) else {
-            return Err(JsmlError::new(&format!("item {route}/{id} not found")));
+            return Err(JsmlError::not_found(&format!(
+                "item {route}/{id} not found"
+            )));
         };
         let Some(body) = body.as_object() else {
-            return Err(JsmlError::new("invalid request body"));
+            return Err(JsmlError::bad_request("invalid request body"));
         };
 
-        item.as_object_mut();
+        let mut patched = item.clone();
         for (key, value) in body {
-            item[key] = value.clone();
+            patched[key] = value.clone();
         }
+        self.schemas.check(route, &patched)?;
+        *item = patched;
         Ok(item.clone())
     }
 
     pub fn post(&mut self, route: &str, body: &Value) -> Result<Value, JsmlError> {
-        let Some(col) = self.database.get_mut(route) else {
-            return Err(JsmlError::new(&format!("collection {route} not found")));
-        };
         let Some(body) = body.as_object() else {
-            return Err(JsmlError::new("invalid request body"));
+            return Err(JsmlError::bad_request("invalid request body"));
+        };
+        let options = &self.options;
+        let Some(Resource::Collection(col)) = self.database.get_mut(route) else {
+            return Err(Self::not_a_collection(route));
         };
         let mut body = body.clone();
-        if let Some(id) = &body.get(&self.id_key) {
-            let Some(id) = id.as_str() else {
-                return Err(JsmlError::new("invalid request body"));
+        let id = if let Some(id) = &body.get(&options.id_key) {
+            let Some(id) = Self::id_string(id) else {
+                return Err(JsmlError::bad_request("invalid request body"));
             };
-            if col.get(id).is_some() {
-                return Err(JsmlError::new(&format!("duplicate id: {id}")));
+            if col.get(&id).is_some() {
+                return Err(JsmlError::conflict(&format!("duplicate id: {id}")));
             }
-            let body = json!(body);
-            col.insert(id.to_string(), body.clone());
-            return Ok(body);
+            id
         } else {
-            let id = Uuid::new_v4().to_string();
-            body.insert(self.id_key.clone(), json!(id));
-            let body = json!(body);
-            col.insert(id.to_string(), body.clone());
-            return Ok(body);
+            let id = Self::generate_id(options, col, |id| col.contains_key(id))?;
+            body.insert(options.id_key.clone(), id.clone());
+            Self::id_string(&id).unwrap_or_default()
+        };
+        let body = json!(body);
+        self.schemas.check(route, &body)?;
+        col.insert(id, body.clone());
+        Ok(body)
+    }
+
+    /// A new id for an item of `collection`, never one for which `taken` holds: a seeded
+    /// run draws the same uuids as the previous one, which saved some of them. Fails once
+    /// incremented ids run past `u64::MAX`.
+    fn generate_id(
+        options: &Options,
+        collection: &IndexMap<String, Value>,
+        taken: impl Fn(&str) -> bool,
+    ) -> Result<Value, JsmlError> {
+        match options.id_strategy {
+            IdStrategy::Uuid => loop {
+                let id = random::uuid(&mut *options.random.lock()).to_string();
+                if !taken(&id) {
+                    return Ok(json!(id));
+                }
+            },
+            IdStrategy::Increment => {
+                let max = collection
+                    .keys()
+                    .filter_map(|key| key.parse::<u64>().ok())
+                    .max()
+                    .unwrap_or(0);
+                let exhausted = || JsmlError::conflict(&format!("no id left after {max}"));
+                let mut id = max.checked_add(1).ok_or_else(exhausted)?;
+                while taken(&id.to_string()) {
+                    id = id.checked_add(1).ok_or_else(exhausted)?;
+                }
+                Ok(json!(id))
+            }
         }
     }
 
-    pub fn serialize_all(&self) -> HashMap<String, Vec<Value>> {
-        let mut response = HashMap::<String, Vec<Value>>::new();
-        for collection in self.database.iter() {
-            response.insert(
-                collection.0.to_string(),
-                Vec::from_iter(collection.1.values().map(|x| x.clone())),
-            );
+    /// Creates an item in `child` whose foreign key points at `parent/id`
+    pub fn post_child(
+        &mut self,
+        parent: &str,
+        id: &str,
+        child: &str,
+        body: &Value,
+    ) -> Result<Value, JsmlError> {
+        let parent_id = self.get(parent, id)?[&self.options.id_key].clone();
+        let Some(body) = body.as_object() else {
+            return Err(JsmlError::bad_request("invalid request body"));
+        };
+        let mut body = body.clone();
+        body.insert(self.foreign_key(parent), parent_id);
+        self.post(child, &Value::Object(body))
+    }
+
+    /// Attaches the children listed in `_embed` and the parents listed in `_expand` to a copy of `item`
+    pub fn embed(&self, route: &str, item: &Value, query: &QueryParams) -> Value {
+        let mut item = item.clone();
+        if query.embed.is_empty() && query.expand.is_empty() {
+            return item;
+        }
+        let id = Self::id_string(&item[&self.options.id_key]);
+        let foreign_key = self.foreign_key(route);
+        for child in &query.embed {
+            let Ok(collection) = self.collection(child) else {
+                continue;
+            };
+            let children: Vec<Value> = collection
+                .values()
+                .filter(|child| id.is_some() && Self::id_string(&child[&foreign_key]) == id)
+                .cloned()
+                .collect();
+            item[child] = Value::Array(children);
+        }
+        for parent in &query.expand {
+            let foreign_key = format!("{parent}{}", self.options.foreign_key_suffix);
+            let Ok(collection) = self.collection(&plural(parent)) else {
+                continue;
+            };
+            let Some(parent_id) = Self::id_string(&item[&foreign_key]) else {
+                continue;
+            };
+            if let Some(document) = collection.get(&parent_id) {
+                item[parent] = document.clone();
+            }
+        }
+        item
+    }
+
+    pub fn serialize_all(&self) -> IndexMap<String, Value> {
+        let mut response = IndexMap::<String, Value>::new();
+        let mut kept = self.kept.iter().peekable();
+        for (route, resource) in &self.database {
+            while let Some((_, key, value)) = kept.next_if(|(at, ..)| *at == response.len()) {
+                response.insert(key.clone(), value.clone());
+            }
+            let value = match resource {
+                Resource::Collection(collection) => {
+                    Value::Array(Vec::from_iter(collection.values().cloned()))
+                }
+                Resource::Singular(value) => value.clone(),
+            };
+            response.insert(route.to_string(), value);
+        }
+        for (_, key, value) in kept {
+            response.insert(key.clone(), value.clone());
         }
         response
     }
 
-    fn match_query(query: &QueryParams, value: &Value) -> bool {
-        query
-            .filters
-            .keys()
-            .all(|key| match Self::get_filtered_field(value, key) {
-                Some(val) => match val {
-                    Value::String(val) => query.filters[key].contains(&val.to_string()),
-                    Value::Number(val) => query.filters[key].contains(&val.to_string()),
-                    Value::Bool(val) => query.filters[key].contains(&val.to_string()),
-                    _ => false,
-                },
-                None => false,
-            })
+    /// Creates every item of `bodies`, or none of them if one is rejected
+    pub fn post_many(&mut self, route: &str, bodies: &[Value]) -> Result<Vec<Value>, JsmlError> {
+        self.atomically(route, |database| {
+            bodies
+                .iter()
+                .enumerate()
+                .map(|(i, body)| {
+                    database.post(route, body).map_err(|mut e| {
+                        e.details = format!("item {i}: {}", e.details);
+                        for violation in &mut e.violations {
+                            violation.path = format!("/{i}{}", violation.path);
+                        }
+                        e
+                    })
+                })
+                .collect()
+        })
+    }
+
+    /// Merges `body` into every item matching the filters and search of `query`. The body
+    /// cannot set the id, which would give every matched item the same one.
+    pub fn patch_many(
+        &mut self,
+        route: &str,
+        query: &QueryParams,
+        body: &Value,
+    ) -> Result<Vec<Value>, JsmlError> {
+        let Some(fields) = body.as_object() else {
+            return Err(JsmlError::bad_request("invalid request body"));
+        };
+        if fields.contains_key(&self.options.id_key) {
+            return Err(JsmlError::bad_request(&format!(
+                "'{}' cannot be changed on several items at once",
+                self.options.id_key
+            )));
+        }
+        let ids: Vec<String> = self
+            .collection(route)?
+            .iter()
+            .filter(|(_, item)| Self::match_query(query, item))
+            .map(|(id, _)| id.clone())
+            .collect();
+        self.atomically(route, |database| {
+            ids.iter()
+                .map(|id| database.patch(route, id, body))
+                .collect()
+        })
     }
 
-    fn get_filtered_field(value: &Value, key: &String) -> Option<Value> {
+    /// Deletes every item matching the filters and search of `query`, returning their ids
+    pub fn delete_many(
+        &mut self,
+        route: &str,
+        query: &QueryParams,
+    ) -> Result<Vec<String>, JsmlError> {
+        let mut deleted = vec![];
+        self.collection_mut(route)?.retain(|id, item| {
+            let matched = Self::match_query(query, item);
+            if matched {
+                deleted.push(id.clone());
+            }
+            !matched
+        });
+        Ok(deleted)
+    }
+
+    /// Runs `change`, restoring `route` as it was if the change fails halfway
+    fn atomically<T>(
+        &mut self,
+        route: &str,
+        change: impl FnOnce(&mut Self) -> Result<T, JsmlError>,
+    ) -> Result<T, JsmlError> {
+        let snapshot = self.database.get(route).cloned();
+        let result = change(self);
+        if let (Err(_), Some(snapshot)) = (&result, snapshot) {
+            self.database.insert(route.to_string(), snapshot);
+        }
+        result
+    }
+
+    /// Id of an item as it appears in its URL
+    pub fn item_id(&self, item: &Value) -> Option<String> {
+        Self::id_string(&item[&self.options.id_key])
+    }
+
+    pub fn get_singular(&self, route: &str) -> Result<&Value, JsmlError> {
+        match self.database.get(route) {
+            Some(Resource::Singular(value)) => Ok(value),
+            _ => Err(JsmlError::not_found(&format!("resource {route} not found"))),
+        }
+    }
+
+    pub fn put_singular(&mut self, route: &str, body: &Value) -> Result<Value, JsmlError> {
+        let Some(Resource::Singular(value)) = self.database.get_mut(route) else {
+            return Err(JsmlError::not_found(&format!("resource {route} not found")));
+        };
+        if !body.is_object() {
+            return Err(JsmlError::bad_request("invalid request body"));
+        }
+        self.schemas.check(route, body)?;
+        *value = body.clone();
+        Ok(value.clone())
+    }
+
+    pub fn patch_singular(&mut self, route: &str, body: &Value) -> Result<Value, JsmlError> {
+        let Some(Resource::Singular(value)) = self.database.get_mut(route) else {
+            return Err(JsmlError::not_found(&format!("resource {route} not found")));
+        };
+        let Some(body) = body.as_object() else {
+            return Err(JsmlError::bad_request("invalid request body"));
+        };
+        let mut patched = value.clone();
+        for (key, field) in body {
+            patched[key] = field.clone();
+        }
+        self.schemas.check(route, &patched)?;
+        *value = patched;
+        Ok(value.clone())
+    }
+
+    fn collection(&self, route: &str) -> Result<&IndexMap<String, Value>, JsmlError> {
+        match self.database.get(route) {
+            Some(Resource::Collection(collection)) => Ok(collection),
+            _ => Err(Self::not_a_collection(route)),
+        }
+    }
+
+    fn collection_mut(&mut self, route: &str) -> Result<&mut IndexMap<String, Value>, JsmlError> {
+        match self.database.get_mut(route) {
+            Some(Resource::Collection(collection)) => Ok(collection),
+            _ => Err(Self::not_a_collection(route)),
+        }
+    }
+
+    fn not_a_collection(route: &str) -> JsmlError {
+        JsmlError::not_found(&format!("collection {route} not found"))
+    }
+
+    /// Whether `value` passes the filters and search of `query`, ignoring pagination
+    pub fn match_query(query: &QueryParams, value: &Value) -> bool {
+        if let Some(term) = &query.search {
+            if !Self::match_search(term, value) {
+                return false;
+            }
+        }
+        query.filters.iter().all(
+            |filter| match Self::get_filtered_field(value, &filter.field) {
+                Some(val) => Self::match_filter(filter, val),
+                None => filter.operator == Operator::Ne,
+            },
+        )
+    }
+
+    /// Looks for `term` (already lowercased) in every string value, at any depth
+    fn match_search(term: &str, value: &Value) -> bool {
+        match value {
+            Value::String(val) => val.to_lowercase().contains(term),
+            Value::Array(values) => values.iter().any(|val| Self::match_search(term, val)),
+            Value::Object(map) => map.values().any(|val| Self::match_search(term, val)),
+            _ => false,
+        }
+    }
+
+    fn match_filter(filter: &Filter, value: &Value) -> bool {
+        let text = match value {
+            Value::String(val) => val.to_string(),
+            Value::Number(val) => val.to_string(),
+            Value::Bool(val) => val.to_string(),
+            _ => return filter.operator == Operator::Ne,
+        };
+        match filter.operator {
+            Operator::Eq | Operator::In => filter.values.contains(&text),
+            Operator::Ne => !filter.values.contains(&text),
+            Operator::Like => filter
+                .patterns
+                .iter()
+                .any(|pattern| pattern.is_match(&text)),
+            Operator::Gt | Operator::Gte | Operator::Lt | Operator::Lte => {
+                filter.values.iter().any(|expected| {
+                    let Some(ordering) = Self::compare(value, expected) else {
+                        return false;
+                    };
+                    match filter.operator {
+                        Operator::Gt => ordering == Ordering::Greater,
+                        Operator::Gte => ordering != Ordering::Less,
+                        Operator::Lt => ordering == Ordering::Less,
+                        _ => ordering != Ordering::Greater,
+                    }
+                })
+            }
+        }
+    }
+
+    /// Numbers compare numerically, strings lexically, anything else is not ordered
+    fn compare(value: &Value, expected: &str) -> Option<Ordering> {
+        match value {
+            Value::Number(val) => {
+                let expected = expected.parse::<f64>().ok()?;
+                val.as_f64()?.partial_cmp(&expected)
+            }
+            Value::String(val) => Some(val.as_str().cmp(expected)),
+            _ => None,
+        }
+    }
+
+    /// Stable sort on each field in turn, keeping nulls and missing fields last
+    fn sort(items: &mut [&Value], sort: &[Sort]) {
+        if sort.is_empty() {
+            return;
+        }
+        items.sort_by(|a, b| {
+            sort.iter()
+                .map(|sort| {
+                    let a = Self::get_filtered_field(a, &sort.field);
+                    let b = Self::get_filtered_field(b, &sort.field);
+                    let (a_rank, b_rank) = (Self::sort_rank(a), Self::sort_rank(b));
+                    if a_rank == NULL_RANK || b_rank == NULL_RANK {
+                        return a_rank.cmp(&b_rank);
+                    }
+                    let ordering = match (a, b) {
+                        (Some(Value::Number(a)), Some(Value::Number(b))) => a
+                            .as_f64()
+                            .partial_cmp(&b.as_f64())
+                            .unwrap_or(Ordering::Equal),
+                        (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+                        (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+                        _ => a_rank.cmp(&b_rank),
+                    };
+                    match sort.order {
+                        Order::Asc => ordering,
+                        Order::Desc => ordering.reverse(),
+                    }
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+    }
+
+    fn sort_rank(value: Option<&Value>) -> u8 {
+        match value {
+            Some(Value::Number(_)) => 0,
+            Some(Value::String(_)) => 1,
+            Some(Value::Bool(_)) => 2,
+            Some(Value::Array(_)) | Some(Value::Object(_)) => 3,
+            Some(Value::Null) | None => NULL_RANK,
+        }
+    }
+
+    /// Name of the field referencing an item of `route` (`posts` -> `postId`)
+    fn foreign_key(&self, route: &str) -> String {
+        format!("{}{}", singular(route), self.options.foreign_key_suffix)
+    }
+
+    /// String form of an identifier or foreign key, as used for lookups
+    fn id_string(value: &Value) -> Option<String> {
+        match value {
+            Value::String(val) => Some(val.to_string()),
+            Value::Number(val) => Some(val.to_string()),
+            _ => None,
+        }
+    }
+
+    fn get_filtered_field<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
         let keys = key.split('.');
         let mut value = value;
-        for (_, key) in keys.enumerate() {
-            let tmp = value.get(key);
-            value = match tmp {
-                Some(val) => val,
-                None => return None,
-            };
+        for key in keys {
+            value = value.get(key)?;
         }
-        Some(value.clone())
+        Some(value)
+    }
+}
+
+/// Naive english singular of a collection name (`comments` -> `comment`, `categories` -> `category`)
+pub fn singular(name: &str) -> String {
+    if let Some(stem) = name.strip_suffix("ies") {
+        format!("{stem}y")
+    } else if let Some(stem) = name.strip_suffix('s') {
+        String::from(stem)
+    } else {
+        String::from(name)
+    }
+}
+
+/// Naive english plural of a resource name (`post` -> `posts`, `category` -> `categories`)
+fn plural(name: &str) -> String {
+    match name.strip_suffix('y') {
+        Some(stem) if !stem.ends_with(['a', 'e', 'i', 'o', 'u']) => format!("{stem}ies"),
+        _ => format!("{name}s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::jsml_error::ErrorKind;
+
+    fn database(data: Value) -> Database {
+        let options = Options {
+            id_key: String::from("id"),
+            id_strategy: IdStrategy::Increment,
+            assign_ids: false,
+            random: Random::new(Some(1)),
+            foreign_key_suffix: String::from("Id"),
+            schemas: Value::Null,
+        };
+        Database::new(&options, &data).expect("valid database")
+    }
+
+    fn ids(database: &Database, pairs: &[(&str, &str)]) -> Vec<String> {
+        let query: HashMap<String, String> = pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let page = database
+            .query("posts", &QueryParams::new(query))
+            .expect("known collection");
+        page.items
+            .iter()
+            .filter_map(|item| Database::id_string(&item["id"]))
+            .collect()
+    }
+
+    #[test]
+    fn filters_compare_numbers_numerically() {
+        let database = database(json!({ "posts": [
+            { "id": "a", "views": 9 },
+            { "id": "b", "views": 10 },
+            { "id": "c", "views": 100 },
+        ]}));
+        assert_eq!(ids(&database, &[("views_gte", "10")]), vec!["b", "c"]);
+        assert_eq!(ids(&database, &[("views_lt", "10")]), vec!["a"]);
+        assert_eq!(ids(&database, &[("views", "9,100")]), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn filters_match_text_and_nested_fields() {
+        let database = database(json!({ "posts": [
+            { "id": "a", "title": "Hello", "author": { "name": "Ann" } },
+            { "id": "b", "title": "world", "author": { "name": "Bob" } },
+            { "id": "c" },
+        ]}));
+        assert_eq!(ids(&database, &[("title_like", "^h")]), vec!["a"]);
+        assert_eq!(ids(&database, &[("title_ne", "Hello")]), vec!["b", "c"]);
+        assert_eq!(ids(&database, &[("author.name", "Bob")]), vec!["b"]);
+    }
+
+    #[test]
+    fn sort_is_type_aware_and_keeps_missing_last() {
+        let database = database(json!({ "posts": [
+            { "id": "a", "views": 10 },
+            { "id": "b" },
+            { "id": "c", "views": 9 },
+            { "id": "d", "views": null },
+            { "id": "e", "views": 100 },
+        ]}));
+        assert_eq!(
+            ids(&database, &[("_sort", "views")]),
+            vec!["c", "a", "e", "b", "d"]
+        );
+        assert_eq!(
+            ids(&database, &[("_sort", "views"), ("_order", "desc")]),
+            vec!["e", "a", "c", "b", "d"]
+        );
+    }
+
+    #[test]
+    fn sort_breaks_ties_with_following_fields() {
+        let database = database(json!({ "posts": [
+            { "id": "a", "group": 1, "title": "y" },
+            { "id": "b", "group": 0, "title": "z" },
+            { "id": "c", "group": 1, "title": "x" },
+        ]}));
+        assert_eq!(
+            ids(
+                &database,
+                &[("_sort", "group,title"), ("_order", "desc,asc")]
+            ),
+            vec!["c", "a", "b"]
+        );
+    }
+
+    fn numbered(count: usize) -> Database {
+        let posts: Vec<Value> = (1..=count).map(|i| json!({ "id": i })).collect();
+        database(json!({ "posts": posts }))
+    }
+
+    #[test]
+    fn page_defaults_to_ten_items() {
+        let database = numbered(12);
+        assert_eq!(ids(&database, &[("_page", "1")]), vec!["11", "12"]);
+        assert_eq!(
+            ids(&database, &[("_page", "1"), ("_limit", "3")]),
+            vec!["4", "5", "6"]
+        );
+        assert!(ids(&database, &[("_page", "5")]).is_empty());
+    }
+
+    #[test]
+    fn start_end_and_limit_slice() {
+        let database = numbered(5);
+        assert_eq!(
+            ids(&database, &[("_start", "1"), ("_end", "3")]),
+            vec!["2", "3"]
+        );
+        assert_eq!(
+            ids(&database, &[("_start", "3"), ("_limit", "5")]),
+            vec!["4", "5"]
+        );
+        assert_eq!(ids(&database, &[("_start", "4")]), vec!["5"]);
+        assert_eq!(ids(&database, &[("_end", "2")]), vec!["1", "2"]);
+        assert_eq!(ids(&database, &[("_limit", "1")]), vec!["1"]);
+        assert!(ids(&database, &[("_start", "3"), ("_end", "1")]).is_empty());
+    }
+
+    #[test]
+    fn huge_offsets_do_not_overflow() {
+        let database = numbered(3);
+        let max = usize::MAX.to_string();
+        assert!(ids(&database, &[("_page", &max), ("_limit", "5")]).is_empty());
+        assert!(ids(&database, &[("_start", &max), ("_limit", "5")]).is_empty());
+        assert_eq!(
+            ids(&database, &[("_start", "1"), ("_limit", &max)]),
+            vec!["2", "3"]
+        );
+    }
+
+    #[test]
+    fn total_counts_matches_before_paging() {
+        let database = numbered(12);
+        let query = QueryParams::new(HashMap::from([
+            (String::from("_page"), String::from("0")),
+            (String::from("id_gt"), String::from("2")),
+        ]));
+        let page = database.query("posts", &query).expect("known collection");
+        assert_eq!(page.total, 10);
+        assert_eq!(page.items.len(), 10);
+    }
+
+    #[test]
+    fn search_looks_into_every_string_case_insensitively() {
+        let database = database(json!({ "posts": [
+            { "id": "a", "title": "Hello", "author": { "name": "Ann" } },
+            { "id": "b", "title": "world", "tags": ["Rust", "annals"] },
+            { "id": "c", "views": 10 },
+        ]}));
+        assert_eq!(ids(&database, &[("q", "ANN")]), vec!["a", "b"]);
+        assert_eq!(
+            ids(&database, &[("q", "rust"), ("title", "world")]),
+            vec!["b"]
+        );
+        assert!(ids(&database, &[("q", "10")]).is_empty());
+    }
+
+    #[test]
+    fn generated_ids_skip_the_ones_taken() {
+        let options = Options {
+            id_key: String::from("id"),
+            id_strategy: IdStrategy::Increment,
+            assign_ids: true,
+            random: Random::new(Some(1)),
+            foreign_key_suffix: String::from("Id"),
+            schemas: Value::Null,
+        };
+        let data = json!({ "posts": [{ "title": "a" }, { "id": 1 }, { "id": 2 }] });
+        let mut database = Database::new(&options, &data).expect("valid database");
+        assert_eq!(ids(&database, &[]), vec!["3", "1", "2"]);
+
+        // A seeded run draws the same uuid again, which must not replace the saved item
+        let taken = random::uuid(&mut *Random::new(Some(7)).lock()).to_string();
+        database.options.id_strategy = IdStrategy::Uuid;
+        database.options.random = Random::new(Some(7));
+        database
+            .post("posts", &json!({ "id": taken }))
+            .expect("new id");
+        let created = database.post("posts", &json!({})).expect("generated id");
+        assert_ne!(created["id"], json!(taken));
+        assert_eq!(
+            database
+                .query("posts", &QueryParams::default())
+                .map(|page| page.total)
+                .ok(),
+            Some(5)
+        );
+    }
+
+    #[test]
+    fn incremented_ids_stop_at_the_largest_integer() {
+        let mut database = database(json!({ "posts": [{ "id": u64::MAX }] }));
+        let err = database
+            .post("posts", &json!({ "title": "a" }))
+            .expect_err("no id left");
+        assert_eq!(err.kind, ErrorKind::Conflict);
+        assert_eq!(ids(&database, &[]), vec![u64::MAX.to_string()]);
+    }
+
+    #[test]
+    fn bulk_post_failing_on_an_item_creates_none() {
+        let mut database = database(json!({ "posts": [{ "id": 1 }] }));
+        let bodies = [json!({ "id": 2 }), json!({ "id": 3 }), json!({ "id": 1 })];
+        let err = database
+            .post_many("posts", &bodies)
+            .expect_err("duplicate id");
+        assert_eq!(err.kind, ErrorKind::Conflict);
+        assert!(err.details.starts_with("item 2:"), "{}", err.details);
+        assert_eq!(ids(&database, &[]), vec!["1"]);
+    }
+
+    #[test]
+    fn bulk_patch_failing_the_schema_partway_changes_none() {
+        let mut database = database(json!({
+            "__schemas": { "posts": { "maxProperties": 2 } },
+            "posts": [{ "id": 1 }, { "id": 2, "draft": true }, { "id": 3 }],
+        }));
+        let err = database
+            .patch_many("posts", &QueryParams::default(), &json!({ "title": "a" }))
+            .expect_err("second item breaks the schema");
+        assert_eq!(err.kind, ErrorKind::Unprocessable);
+        for id in ["1", "2", "3"] {
+            let item = database.get("posts", id).expect("kept item");
+            assert_eq!(item.get("title"), None, "item {id}");
+        }
+    }
+
+    #[test]
+    fn violations_point_at_the_offending_values() {
+        let mut database = database(json!({
+            "__schemas": {
+                "posts": {
+                    "properties": {
+                        "title": { "type": "string" },
+                        "tags": { "items": { "type": "string" } },
+                    },
+                },
+            },
+            "posts": [],
+        }));
+        let err = database
+            .post("posts", &json!({ "id": 1, "tags": ["a", 2] }))
+            .expect_err("tag is not a string");
+        assert_eq!(err.kind, ErrorKind::Unprocessable);
+        let paths: Vec<&str> = err.violations.iter().map(|v| v.path.as_str()).collect();
+        assert_eq!(paths, vec!["/tags/1"]);
+
+        let bodies = [
+            json!({ "id": 1, "title": "a" }),
+            json!({ "id": 2, "title": 3 }),
+        ];
+        let err = database
+            .post_many("posts", &bodies)
+            .expect_err("title is not a string");
+        assert_eq!(err.kind, ErrorKind::Unprocessable);
+        let paths: Vec<&str> = err.violations.iter().map(|v| v.path.as_str()).collect();
+        assert_eq!(paths, vec!["/1/title"]);
     }
 }
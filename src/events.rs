@@ -0,0 +1,110 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+/// Number of past events kept for clients resuming with `Last-Event-ID`
+const HISTORY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// A change made to an item, or to a singular resource when `item_id` is `None`
+#[derive(Debug)]
+pub struct Event {
+    pub id: u64,
+    pub kind: EventKind,
+    pub collection: String,
+    pub item_id: Option<String>,
+    /// The document after the change, `None` once deleted
+    pub document: Option<Value>,
+}
+
+impl Event {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "type": self.kind,
+            "collection": self.collection,
+            "id": self.item_id,
+            "document": self.document,
+        })
+    }
+
+    /// Formats the event as a Server-Sent Events message
+    pub fn to_sse(&self) -> String {
+        let kind = match self.kind {
+            EventKind::Created => "created",
+            EventKind::Updated => "updated",
+            EventKind::Deleted => "deleted",
+        };
+        format!(
+            "id: {}\nevent: {kind}\ndata: {}\n\n",
+            self.id,
+            self.to_json()
+        )
+    }
+}
+
+/// Change feed of the database: live subscribers plus a bounded history of past events
+pub struct Events {
+    sender: broadcast::Sender<Arc<Event>>,
+    history: VecDeque<Arc<Event>>,
+    next_id: u64,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(HISTORY);
+        Self {
+            sender,
+            history: VecDeque::with_capacity(HISTORY),
+            next_id: 1,
+        }
+    }
+
+    pub fn publish(
+        &mut self,
+        kind: EventKind,
+        collection: &str,
+        item_id: Option<String>,
+        document: Option<Value>,
+    ) {
+        let event = Arc::new(Event {
+            id: self.next_id,
+            kind,
+            collection: collection.to_string(),
+            item_id,
+            document,
+        });
+        self.next_id += 1;
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(event.clone());
+        // Nobody listening is not an error
+        let _ = self.sender.send(event);
+    }
+
+    /// Events published after `last_id`, along with a receiver of the ones to come
+    pub fn subscribe(
+        &self,
+        last_id: Option<u64>,
+    ) -> (Vec<Arc<Event>>, broadcast::Receiver<Arc<Event>>) {
+        let missed = match last_id {
+            Some(last_id) => self
+                .history
+                .iter()
+                .filter(|event| event.id > last_id)
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+        (missed, self.sender.subscribe())
+    }
+}
@@ -0,0 +1,409 @@
+use rand::{seq::SliceRandom, Rng};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    jsml_error::JsmlError,
+    random::{self, Random},
+};
+
+/// Key of a template object giving how many items it expands to
+const REPEAT: &str = "$repeat";
+/// Placeholder replaced by the position of the item within its `$repeat`
+const INDEX: &str = "$index";
+
+const FIRST_NAMES: [&str; 20] = [
+    "Alice", "Bob", "Chloe", "David", "Emma", "Felix", "Grace", "Hugo", "Iris", "Jack", "Karen",
+    "Liam", "Maya", "Noah", "Olivia", "Paul", "Quinn", "Rose", "Sam", "Tara",
+];
+const LAST_NAMES: [&str; 16] = [
+    "Martin", "Smith", "Bernard", "Johnson", "Dubois", "Brown", "Garcia", "Miller", "Lefebvre",
+    "Davis", "Moreau", "Wilson", "Lopez", "Taylor", "Roux", "Clark",
+];
+const CITIES: [&str; 12] = [
+    "Paris", "London", "Berlin", "Madrid", "Lisbon", "Rome", "Oslo", "Vienna", "Prague", "Dublin",
+    "Montreal", "Tokyo",
+];
+const COUNTRIES: [&str; 10] = [
+    "France",
+    "United Kingdom",
+    "Germany",
+    "Spain",
+    "Portugal",
+    "Italy",
+    "Norway",
+    "Austria",
+    "Canada",
+    "Japan",
+];
+const STREETS: [&str; 8] = [
+    "Main Street",
+    "High Street",
+    "Church Road",
+    "Station Road",
+    "Park Avenue",
+    "Mill Lane",
+    "Rue de la Paix",
+    "Elm Street",
+];
+const COMPANY_SUFFIXES: [&str; 5] = ["Inc", "LLC", "Group", "Labs", "& Co"];
+const DOMAINS: [&str; 4] = ["example.com", "example.org", "mail.test", "demo.dev"];
+const WORDS: [&str; 24] = [
+    "lorem",
+    "ipsum",
+    "dolor",
+    "sit",
+    "amet",
+    "consectetur",
+    "adipiscing",
+    "elit",
+    "sed",
+    "do",
+    "eiusmod",
+    "tempor",
+    "incididunt",
+    "ut",
+    "labore",
+    "et",
+    "dolore",
+    "magna",
+    "aliqua",
+    "enim",
+    "minim",
+    "veniam",
+    "quis",
+    "nostrud",
+];
+
+/// Expands a template: objects of an array carrying `"$repeat": n` are replaced by `n`
+/// copies of themselves, and `{{name.firstName}}`-style placeholders in strings are replaced
+/// by fake data. A string made of a single placeholder keeps the placeholder's JSON type.
+pub fn expand(template: &Value, random: &Random) -> Result<Value, JsmlError> {
+    let now = random.now();
+    expand_value(template, None, now, &mut *random.lock())
+}
+
+fn expand_value(
+    value: &Value,
+    index: Option<u64>,
+    now: i64,
+    rng: &mut impl Rng,
+) -> Result<Value, JsmlError> {
+    match value {
+        Value::String(text) => render(text, index, now, rng),
+        Value::Object(map) => {
+            let mut result = Map::new();
+            for (key, value) in map {
+                result.insert(key.clone(), expand_value(value, index, now, rng)?);
+            }
+            Ok(Value::Object(result))
+        }
+        Value::Array(items) => {
+            let mut result = vec![];
+            for item in items {
+                let Some(count) = item.get(REPEAT) else {
+                    result.push(expand_value(item, index, now, rng)?);
+                    continue;
+                };
+                let Some(count) = count.as_u64() else {
+                    return Err(JsmlError::bad_request(&format!(
+                        "'{REPEAT}' must be a positive integer, found {count}"
+                    )));
+                };
+                let mut item = item.clone();
+                if let Some(item) = item.as_object_mut() {
+                    item.retain(|key, _| key != REPEAT);
+                }
+                for i in 0..count {
+                    result.push(expand_value(&item, Some(i), now, rng)?);
+                }
+            }
+            Ok(Value::Array(result))
+        }
+        _ => Ok(value.clone()),
+    }
+}
+
+fn render(
+    text: &str,
+    index: Option<u64>,
+    now: i64,
+    rng: &mut impl Rng,
+) -> Result<Value, JsmlError> {
+    let trimmed = text.trim();
+    if let Some(name) = trimmed
+        .strip_prefix("{{")
+        .and_then(|rest| rest.strip_suffix("}}"))
+        .filter(|name| !name.contains("{{"))
+    {
+        return fake(name.trim(), index, now, rng);
+    }
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        match fake(rest[start + 2..start + end].trim(), index, now, rng)? {
+            Value::String(value) => result.push_str(&value),
+            value => result.push_str(&value.to_string()),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    result.push_str(rest);
+    Ok(Value::String(result))
+}
+
+/// Produces the value of a single placeholder, e.g. `number.int(1, 10)`
+fn fake(
+    placeholder: &str,
+    index: Option<u64>,
+    now: i64,
+    rng: &mut impl Rng,
+) -> Result<Value, JsmlError> {
+    let (name, args) = match placeholder.split_once('(') {
+        Some((name, args)) => (name.trim(), args.trim_end_matches(')')),
+        None => (placeholder, ""),
+    };
+    let args: Vec<f64> = args
+        .split(',')
+        .filter_map(|arg| arg.trim().parse::<f64>().ok())
+        .collect();
+    let pick = |rng: &mut _, list: &[&str]| String::from(*list.choose(rng).unwrap_or(&""));
+    let value = match name {
+        INDEX => json!(index.unwrap_or(0)),
+        "name.firstName" => json!(pick(rng, &FIRST_NAMES)),
+        "name.lastName" => json!(pick(rng, &LAST_NAMES)),
+        "name.fullName" => json!(format!(
+            "{} {}",
+            pick(rng, &FIRST_NAMES),
+            pick(rng, &LAST_NAMES)
+        )),
+        "internet.userName" => json!(user_name(rng)),
+        "internet.email" => json!(format!("{}@{}", user_name(rng), pick(rng, &DOMAINS))),
+        "internet.url" => json!(format!("https://{}", pick(rng, &DOMAINS))),
+        "address.city" => json!(pick(rng, &CITIES)),
+        "address.country" => json!(pick(rng, &COUNTRIES)),
+        "address.street" => json!(format!("{} {}", rng.gen_range(1..300), pick(rng, &STREETS))),
+        "company.name" => json!(format!(
+            "{} {}",
+            pick(rng, &LAST_NAMES),
+            pick(rng, &COMPANY_SUFFIXES)
+        )),
+        "phone.number" => json!(format!(
+            "+1-{:03}-{:03}-{:04}",
+            rng.gen_range(200..1000),
+            rng.gen_range(0..1000),
+            rng.gen_range(0..10000)
+        )),
+        "lorem.word" => json!(pick(rng, &WORDS)),
+        "lorem.words" => json!(words(rng, args.first().map_or(3, |n| *n as usize))),
+        "lorem.sentence" => {
+            let count = rng.gen_range(6..12);
+            json!(sentence(&words(rng, count)))
+        }
+        "lorem.paragraph" => {
+            let sentences: Vec<String> = (0..rng.gen_range(3..6))
+                .map(|_| {
+                    let count = rng.gen_range(6..12);
+                    sentence(&words(rng, count))
+                })
+                .collect();
+            json!(sentences.join(" "))
+        }
+        "number.int" => {
+            let (min, max) = bounds(name, &args, 0.0, 1000.0)?;
+            // Bounds past i64 would silently saturate when converted
+            let limit = i64::MAX as f64;
+            if min < -limit || max > limit {
+                return Err(JsmlError::bad_request(&format!(
+                    "{name} needs bounds within ±{limit:e}"
+                )));
+            }
+            json!(rng.gen_range(min as i64..=max as i64))
+        }
+        "number.float" => {
+            let (min, max) = bounds(name, &args, 0.0, 1000.0)?;
+            let value: f64 = rng.gen_range(min..=max);
+            // Values too large for cents have no decimals to round anyway
+            let rounded = (value * 100.0).round() / 100.0;
+            json!(if rounded.is_finite() { rounded } else { value })
+        }
+        "datatype.boolean" => json!(rng.gen_bool(0.5)),
+        "date.past" => json!(date(rng, now, -1)),
+        "date.future" => json!(date(rng, now, 1)),
+        "string.uuid" => json!(random::uuid(rng).to_string()),
+        _ => {
+            return Err(JsmlError::bad_request(&format!(
+                "unknown placeholder {{{{{placeholder}}}}}"
+            )))
+        }
+    };
+    Ok(value)
+}
+
+/// Range given by the first two `args` of `name`, `min` and `max` by default, rejecting
+/// the ones too wide to draw from
+fn bounds(name: &str, args: &[f64], min: f64, max: f64) -> Result<(f64, f64), JsmlError> {
+    let min = args.first().copied().unwrap_or(min);
+    let max = args.get(1).copied().unwrap_or(max).max(min);
+    if !(max - min).is_finite() {
+        return Err(JsmlError::bad_request(&format!(
+            "{name} needs finite bounds no further apart than {:e}",
+            f64::MAX
+        )));
+    }
+    Ok((min, max))
+}
+
+fn user_name(rng: &mut impl Rng) -> String {
+    let first = FIRST_NAMES.choose(rng).unwrap_or(&"user");
+    let last = LAST_NAMES.choose(rng).unwrap_or(&"name");
+    format!("{}.{}{}", first, last, rng.gen_range(1..100)).to_lowercase()
+}
+
+fn words(rng: &mut impl Rng, count: usize) -> String {
+    let words: Vec<&str> = (0..count)
+        .map(|_| *WORDS.choose(rng).unwrap_or(&"lorem"))
+        .collect();
+    words.join(" ")
+}
+
+fn sentence(words: &str) -> String {
+    let mut chars = words.chars();
+    match chars.next() {
+        Some(first) => format!("{}{}.", first.to_uppercase(), chars.as_str()),
+        None => String::new(),
+    }
+}
+
+/// A random RFC 3339 timestamp within a year before (`direction` -1) or after (1) `now`
+fn date(rng: &mut impl Rng, now: i64, direction: i64) -> String {
+    rfc3339(now + direction * rng.gen_range(0..365 * 24 * 3600))
+}
+
+/// RFC 3339 form of a number of seconds since the epoch, in UTC
+fn rfc3339(timestamp: i64) -> String {
+    let (days, seconds) = (timestamp.div_euclid(86400), timestamp.rem_euclid(86400));
+    // Days since the epoch to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn rng() -> StdRng {
+        StdRng::seed_from_u64(7)
+    }
+
+    #[test]
+    fn rfc3339_converts_civil_dates() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(-1), "1969-12-31T23:59:59Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1_704_067_200), "2024-01-01T00:00:00Z");
+        assert_eq!(rfc3339(4_107_542_399), "2100-02-28T23:59:59Z");
+    }
+
+    #[test]
+    fn dates_stay_within_a_year() {
+        let now = 1_704_067_200;
+        let mut rng = rng();
+        for _ in 0..100 {
+            let past = date(&mut rng, now, -1);
+            let future = date(&mut rng, now, 1);
+            assert!(past.as_str() >= "2023-01-01" && past.as_str() <= "2024-01-01T00:00:00Z");
+            assert!(future.as_str() >= "2024-01-01" && future.as_str() < "2025-01-01");
+        }
+    }
+
+    #[test]
+    fn single_placeholder_keeps_its_type() {
+        let mut rng = rng();
+        let value = render("{{number.int(3, 3)}}", None, 0, &mut rng).expect("known placeholder");
+        assert_eq!(value, json!(3));
+        let value = render(" {{ $index }} ", Some(4), 0, &mut rng).expect("known placeholder");
+        assert_eq!(value, json!(4));
+        let value = render("{{datatype.boolean}}", None, 0, &mut rng).expect("known placeholder");
+        assert!(value.is_boolean());
+    }
+
+    #[test]
+    fn placeholders_within_text_are_replaced() {
+        let mut rng = rng();
+        let value = render("#{{$index}}: {{number.int(5, 5)}}", Some(2), 0, &mut rng)
+            .expect("known placeholders");
+        assert_eq!(value, json!("#2: 5"));
+        let value = render("{{unclosed", None, 0, &mut rng).expect("no placeholder");
+        assert_eq!(value, json!("{{unclosed"));
+    }
+
+    #[test]
+    fn unknown_placeholder_is_an_error() {
+        assert!(render("{{nope}}", None, 0, &mut rng()).is_err());
+    }
+
+    #[test]
+    fn number_bounds_must_be_finite_and_drawable() {
+        let mut rng = rng();
+        for placeholder in [
+            "{{number.float(0, inf)}}",
+            "{{number.float(NaN, 1)}}",
+            "{{number.float(-1e308, 1e308)}}",
+            "{{number.int(0, 1e19)}}",
+            "{{number.int(-inf, 0)}}",
+        ] {
+            assert!(
+                render(placeholder, None, 0, &mut rng).is_err(),
+                "{placeholder}"
+            );
+        }
+        let value =
+            render("{{number.float(-1e307, 1e307)}}", None, 0, &mut rng).expect("drawable range");
+        assert!(value.is_number());
+    }
+
+    #[test]
+    fn repeat_copies_objects_with_their_index() {
+        let template = json!({ "users": [
+            { "$repeat": 3, "id": "{{$index}}" },
+            { "id": "last" },
+        ]});
+        let expanded = expand(&template, &Random::new(Some(1))).expect("valid template");
+        assert_eq!(
+            expanded,
+            json!({ "users": [{ "id": 0 }, { "id": 1 }, { "id": 2 }, { "id": "last" }] })
+        );
+    }
+
+    #[test]
+    fn repeat_must_be_a_positive_integer() {
+        let template = json!({ "users": [{ "$repeat": -1 }] });
+        assert!(expand(&template, &Random::new(Some(1))).is_err());
+    }
+
+    #[test]
+    fn seeded_expansion_is_reproducible() {
+        let template = json!({ "users": [{ "$repeat": 5, "name": "{{name.fullName}}" }] });
+        let first = expand(&template, &Random::new(Some(42))).expect("valid template");
+        let second = expand(&template, &Random::new(Some(42))).expect("valid template");
+        assert_eq!(first, second);
+    }
+}
@@ -1,16 +1,91 @@
 use std::{error, fmt};
 
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::json;
+
+/// What went wrong, deciding the HTTP status of the response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    Unprocessable,
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::BadRequest => "bad_request",
+            Self::NotFound => "not_found",
+            Self::MethodNotAllowed => "method_not_allowed",
+            Self::Conflict => "conflict",
+            Self::Unprocessable => "unprocessable_entity",
+            Self::Internal => "internal_error",
+        }
+    }
+}
+
+/// A value breaking a rule of a JSON Schema
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    /// JSON pointer to the value, e.g. `/tags/0`
+    pub path: String,
+    pub message: String,
+}
+
 #[derive(Debug)]
 pub struct JsmlError {
+    pub kind: ErrorKind,
     pub details: String,
+    /// Request path the error is reported for
+    pub path: Option<String>,
+    pub violations: Vec<Violation>,
 }
 
 impl JsmlError {
-    pub fn new(msg: &str) -> Self {
+    pub fn new(kind: ErrorKind, msg: &str) -> Self {
         Self {
+            kind,
             details: msg.to_string(),
+            path: None,
+            violations: vec![],
+        }
+    }
+
+    pub fn bad_request(msg: &str) -> Self {
+        Self::new(ErrorKind::BadRequest, msg)
+    }
+
+    pub fn not_found(msg: &str) -> Self {
+        Self::new(ErrorKind::NotFound, msg)
+    }
+
+    pub fn method_not_allowed(msg: &str) -> Self {
+        Self::new(ErrorKind::MethodNotAllowed, msg)
+    }
+
+    pub fn conflict(msg: &str) -> Self {
+        Self::new(ErrorKind::Conflict, msg)
+    }
+
+    pub fn unprocessable(msg: &str, violations: Vec<Violation>) -> Self {
+        Self {
+            violations,
+            ..Self::new(ErrorKind::Unprocessable, msg)
         }
     }
+
+    pub fn internal(msg: &str) -> Self {
+        Self::new(ErrorKind::Internal, msg)
+    }
+
+    pub fn at(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
 }
 
 impl error::Error for JsmlError {
@@ -25,14 +100,39 @@ impl fmt::Display for JsmlError {
     }
 }
 
+impl ResponseError for JsmlError {
+    fn status_code(&self) -> StatusCode {
+        match self.kind {
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::Unprocessable => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut error = json!({
+            "code": self.kind.code(),
+            "message": self.details,
+            "path": self.path,
+        });
+        if !self.violations.is_empty() {
+            error["violations"] = json!(self.violations);
+        }
+        HttpResponse::build(self.status_code()).json(json!({ "error": error }))
+    }
+}
+
 impl From<JsmlError> for std::io::Error {
     fn from(err: JsmlError) -> Self {
-        Self::new(std::io::ErrorKind::Other, err.details.as_str())
+        Self::other(err.details)
     }
 }
 
 impl From<std::io::Error> for JsmlError {
     fn from(err: std::io::Error) -> Self {
-        Self::new(&err.to_string())
+        Self::internal(&err.to_string())
     }
 }
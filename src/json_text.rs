@@ -0,0 +1,447 @@
+use serde::Serialize;
+use serde_json::{ser::PrettyFormatter, Map, Value};
+
+/// Number of following elements searched for the unchanged copy of an array element, so
+/// removals keep the text of the elements after them
+const LOOKAHEAD: usize = 64;
+
+/// Where a value lies in the text of a document, along with its elements or entries
+#[derive(Debug)]
+struct Span {
+    start: usize,
+    end: usize,
+    children: Children,
+}
+
+#[derive(Debug)]
+enum Children {
+    Scalar,
+    Array(Vec<Span>),
+    Object(Vec<Entry>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: String,
+    /// Offsets of the quoted key in the text
+    key_start: usize,
+    key_end: usize,
+    value: Span,
+}
+
+/// A JSON text along with the value it holds. `render` writes a new value reusing the text
+/// of every part left unchanged, so a single edit only rewrites the lines of that edit.
+#[derive(Debug)]
+pub struct JsonText {
+    text: String,
+    value: Value,
+    root: Span,
+}
+
+impl JsonText {
+    pub fn parse(text: String) -> Option<Self> {
+        let value = serde_json::from_str(&text).ok()?;
+        let mut parser = Parser {
+            text: &text,
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let root = parser.value()?;
+        Some(Self { text, value, root })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// `value` as JSON, copying the text of the parts equal to the ones of this document and
+    /// formatting the others with `indent`
+    pub fn render(&self, value: &Value, indent: &[u8]) -> String {
+        let renderer = Renderer {
+            text: &self.text,
+            indent,
+        };
+        let mut out = String::with_capacity(self.text.len());
+        out.push_str(&self.text[..self.root.start]);
+        let line = renderer.line_indent(self.root.start);
+        renderer.value(&mut out, value, &self.value, &self.root, line);
+        out.push_str(&self.text[self.root.end..]);
+        out
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn value(&mut self) -> Option<Span> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let children = match self.bytes.get(self.pos)? {
+            b'{' => self.object()?,
+            b'[' => self.array()?,
+            b'"' => {
+                self.string()?;
+                Children::Scalar
+            }
+            _ => {
+                while self.bytes.get(self.pos).is_some_and(|byte| {
+                    !matches!(byte, b',' | b']' | b'}') && !byte.is_ascii_whitespace()
+                }) {
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return None;
+                }
+                Children::Scalar
+            }
+        };
+        Some(Span {
+            start,
+            end: self.pos,
+            children,
+        })
+    }
+
+    fn array(&mut self) -> Option<Children> {
+        self.pos += 1;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.eat(b']') {
+            return Some(Children::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat(b']') {
+                return Some(Children::Array(items));
+            }
+            if !self.eat(b',') {
+                return None;
+            }
+        }
+    }
+
+    fn object(&mut self) -> Option<Children> {
+        self.pos += 1;
+        let mut entries = vec![];
+        self.skip_whitespace();
+        if self.eat(b'}') {
+            return Some(Children::Object(entries));
+        }
+        loop {
+            self.skip_whitespace();
+            let key_start = self.pos;
+            if self.bytes.get(self.pos) != Some(&b'"') {
+                return None;
+            }
+            self.string()?;
+            let key_end = self.pos;
+            let key = serde_json::from_str(&self.text[key_start..key_end]).ok()?;
+            self.skip_whitespace();
+            if !self.eat(b':') {
+                return None;
+            }
+            let value = self.value()?;
+            entries.push(Entry {
+                key,
+                key_start,
+                key_end,
+                value,
+            });
+            self.skip_whitespace();
+            if self.eat(b'}') {
+                return Some(Children::Object(entries));
+            }
+            if !self.eat(b',') {
+                return None;
+            }
+        }
+    }
+
+    /// Skips a string, the cursor being on its opening quote
+    fn string(&mut self) -> Option<()> {
+        self.pos += 1;
+        loop {
+            match self.bytes.get(self.pos)? {
+                b'\\' => self.pos += 2,
+                b'"' => {
+                    self.pos += 1;
+                    return Some(());
+                }
+                _ => self.pos += 1,
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.bytes.get(self.pos) == Some(&byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+}
+
+struct Renderer<'a> {
+    text: &'a str,
+    indent: &'a [u8],
+}
+
+impl<'a> Renderer<'a> {
+    /// Writes `new`, which takes the place of `old` found at `span` on a line indented by `line`
+    fn value(&self, out: &mut String, new: &Value, old: &Value, span: &Span, line: &str) {
+        if new == old {
+            out.push_str(&self.text[span.start..span.end]);
+            return;
+        }
+        match (new, old, &span.children) {
+            (Value::Object(new), Value::Object(old), Children::Object(entries))
+                if !entries.is_empty() && !new.is_empty() =>
+            {
+                self.object(out, new, old, span, entries);
+            }
+            (Value::Array(new), Value::Array(old), Children::Array(items))
+                if !items.is_empty() && !new.is_empty() =>
+            {
+                self.array(out, new, old, span, items);
+            }
+            _ => self.fresh(out, new, line),
+        }
+    }
+
+    fn object(
+        &self,
+        out: &mut String,
+        new: &Map<String, Value>,
+        old: &Map<String, Value>,
+        span: &Span,
+        entries: &[Entry],
+    ) {
+        let starts: Vec<usize> = entries.iter().map(|entry| entry.key_start).collect();
+        let ends: Vec<usize> = entries.iter().map(|entry| entry.value.end).collect();
+        let layout = Container::new(self, span, &starts, &ends);
+        // Entries added to the object copy the spacing around the colon of the first one
+        let colon = &self.text[entries[0].key_end..entries[0].value.start];
+        out.push('{');
+        for (i, (key, value)) in new.iter().enumerate() {
+            let position = entries.iter().position(|entry| entry.key == *key);
+            out.push_str(layout.separator(i, position));
+            match (position, old.get(key)) {
+                (Some(position), Some(old)) => {
+                    let entry = &entries[position];
+                    out.push_str(&self.text[entry.key_start..entry.value.start]);
+                    let line = self.line_indent(entry.key_start);
+                    self.value(out, value, old, &entry.value, line);
+                }
+                _ => {
+                    out.push_str(&Value::String(key.clone()).to_string());
+                    out.push_str(colon);
+                    layout.fresh(self, out, value);
+                }
+            }
+        }
+        out.push_str(layout.trailing);
+        out.push('}');
+    }
+
+    fn array(&self, out: &mut String, new: &[Value], old: &[Value], span: &Span, items: &[Span]) {
+        let starts: Vec<usize> = items.iter().map(|item| item.start).collect();
+        let ends: Vec<usize> = items.iter().map(|item| item.end).collect();
+        let layout = Container::new(self, span, &starts, &ends);
+        let mut next = 0;
+        out.push('[');
+        for (i, value) in new.iter().enumerate() {
+            // The same element further on means the ones before it were removed, otherwise the
+            // next element was changed in place unless it is the one following `value`
+            let unchanged = (next..old.len().min(next + LOOKAHEAD)).find(|&j| old[j] == *value);
+            let template = unchanged.or_else(|| {
+                (next < old.len() && new.get(i + 1) != Some(&old[next])).then_some(next)
+            });
+            out.push_str(layout.separator(i, template));
+            match template {
+                Some(j) => {
+                    let line = self.line_indent(items[j].start);
+                    self.value(out, value, &old[j], &items[j], line);
+                    next = j + 1;
+                }
+                None => layout.fresh(self, out, value),
+            }
+        }
+        out.push_str(layout.trailing);
+        out.push(']');
+    }
+
+    /// Pretty prints `value` as if it started on a line indented by `line`
+    fn fresh(&self, out: &mut String, value: &Value, line: &str) {
+        let mut content = vec![];
+        let formatter = PrettyFormatter::with_indent(self.indent);
+        let mut serializer = serde_json::Serializer::with_formatter(&mut content, formatter);
+        // Serializing a `Value` into memory cannot fail
+        let _ = value.serialize(&mut serializer);
+        let content = String::from_utf8_lossy(&content);
+        out.push_str(&content.replace('\n', &format!("\n{line}")));
+    }
+
+    /// Whitespace starting the line holding `position`
+    fn line_indent(&self, position: usize) -> &'a str {
+        let line_start = self.text[..position].rfind('\n').map_or(0, |i| i + 1);
+        let line = &self.text[line_start..position];
+        &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+    }
+}
+
+/// Whitespace and commas of a non-empty array or object, reused for its new content
+struct Container<'a> {
+    /// Text before each child: the opening whitespace, then a comma and whitespace
+    separators: Vec<&'a str>,
+    trailing: &'a str,
+    /// Separator of the children that did not exist
+    default_separator: String,
+    /// Indentation of the children, `None` when they share the line of the container
+    child_line: Option<String>,
+}
+
+impl<'a> Container<'a> {
+    fn new(renderer: &Renderer<'a>, span: &Span, starts: &[usize], ends: &[usize]) -> Self {
+        let text = renderer.text;
+        let mut separators = vec![&text[span.start + 1..starts[0]]];
+        for (end, start) in ends.iter().zip(&starts[1..]) {
+            separators.push(&text[*end..*start]);
+        }
+        let trailing = &text[ends[ends.len() - 1]..span.end - 1];
+        let child_line = separators[0]
+            .rfind('\n')
+            .map(|i| String::from(&separators[0][i + 1..]));
+        let default_separator = match (separators.get(1), &child_line) {
+            (Some(separator), _) => String::from(*separator),
+            (None, Some(child_line)) => format!(",\n{child_line}"),
+            (None, None) => String::from(", "),
+        };
+        Self {
+            separators,
+            trailing,
+            default_separator,
+            child_line,
+        }
+    }
+
+    /// Text before the `index`th child, which was the `original`th one of the container
+    fn separator(&self, index: usize, original: Option<usize>) -> &str {
+        match (index, original) {
+            (0, _) => self.separators[0],
+            (_, Some(original)) if original > 0 => self.separators[original],
+            _ => &self.default_separator,
+        }
+    }
+
+    /// Writes a child that did not exist, on one line when the container holds its children so
+    fn fresh(&self, renderer: &Renderer, out: &mut String, value: &Value) {
+        match &self.child_line {
+            Some(line) => renderer.fresh(out, value, line),
+            None => out.push_str(&value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const SOURCE: &str = r#"{
+  "posts": [
+    { "id": "1", "title": "a" },
+    {
+        "id": "2",
+        "title": "b"
+    }
+  ],
+  "profile": {"name": "x"}
+}
+"#;
+
+    fn render(source: &str, change: impl FnOnce(&mut Value)) -> String {
+        let text = JsonText::parse(String::from(source)).expect("valid JSON");
+        let mut value = text.value().clone();
+        change(&mut value);
+        let rendered = text.render(&value, b"  ");
+        assert_eq!(
+            serde_json::from_str::<Value>(&rendered).expect("valid output"),
+            value
+        );
+        rendered
+    }
+
+    #[test]
+    fn unchanged_value_is_rendered_as_is() {
+        assert_eq!(render(SOURCE, |_| {}), SOURCE);
+    }
+
+    #[test]
+    fn change_only_rewrites_its_value() {
+        let rendered = render(SOURCE, |value| value["posts"][1]["title"] = json!("c"));
+        assert_eq!(
+            rendered,
+            SOURCE.replace(r#""title": "b""#, r#""title": "c""#)
+        );
+        let reverted = render(&rendered, |value| value["posts"][1]["title"] = json!("b"));
+        assert_eq!(reverted, SOURCE);
+    }
+
+    #[test]
+    fn removed_element_keeps_the_others() {
+        let rendered = render(SOURCE, |value| {
+            value["posts"].as_array_mut().map(|posts| posts.remove(0));
+        });
+        assert_eq!(
+            rendered,
+            "{\n  \"posts\": [\n    {\n        \"id\": \"2\",\n        \"title\": \"b\"\n    }\n  ],\n  \"profile\": {\"name\": \"x\"}\n}\n"
+        );
+    }
+
+    #[test]
+    fn added_values_follow_their_container() {
+        let rendered = render(SOURCE, |value| {
+            value["profile"]["tags"] = json!(["t"]);
+            if let Some(posts) = value["posts"].as_array_mut() {
+                posts.push(json!({ "id": "3" }));
+            }
+        });
+        assert!(rendered.contains("\"profile\": {\"name\": \"x\", \"tags\": [\"t\"]}"));
+        assert!(rendered.contains("    },\n    {\n      \"id\": \"3\"\n    }\n  ],"));
+    }
+
+    #[test]
+    fn empty_containers_are_formatted() {
+        let rendered = render("{\"posts\": []}", |value| {
+            value["posts"] = json!([{ "id": 1 }])
+        });
+        assert_eq!(rendered, "{\"posts\": [\n  {\n    \"id\": 1\n  }\n]}");
+    }
+
+    #[test]
+    fn escaped_keys_and_strings_are_kept() {
+        let source = "{ \"a\\\"b\": \"\\u00e9\", \"n\": 1.0 }";
+        let rendered = render(source, |value| value["n"] = json!(2));
+        assert_eq!(rendered, "{ \"a\\\"b\": \"\\u00e9\", \"n\": 2 }");
+    }
+}
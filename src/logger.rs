@@ -47,3 +47,13 @@ impl LogEntry for RouteEntry {
         println!("{self}");
     }
 }
+
+/// Reports an event that happened outside of a request
+pub fn info(context: &str, message: &dyn Display) {
+    println!("INFO\t- {context}\t- {message}");
+}
+
+/// Reports a failure that happened outside of a request, e.g. in a background task
+pub fn error(context: &str, err: &dyn Display) {
+    eprintln!("ERROR\t- {context}\t- {err}");
+}
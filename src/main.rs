@@ -1,24 +1,47 @@
 #![warn(clippy::all, clippy::perf)]
 
-use std::{io::Error, sync::Mutex};
+use std::io::{Error, Write};
 
 use actix_cors::Cors;
 use actix_web::{middleware, web, App, HttpServer};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use database::IdStrategy;
+use parking_lot::RwLock;
+use schema::SchemaFormat;
+use source::{Format, Persist, Source};
 
+mod admin;
 mod database;
+mod events;
+mod generator;
 mod jsml_error;
+mod json_text;
 mod logger;
+mod openapi;
+mod random;
 mod routes;
+mod schema;
 mod source;
 mod state;
+mod validation;
+mod watcher;
+mod ws;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
-    /// Path of the source json file
-    #[arg()]
-    source: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path of the source file, in JSON, JSON5, YAML or TOML, or of a directory
+    /// holding one such file per collection
+    #[arg(required = true)]
+    source: Option<String>,
+
+    /// Format of the source file, guessed from its extension by default
+    #[arg(long, value_enum)]
+    format: Option<Format>,
 
     /// Port default: 4242
     #[arg(short, long, default_value_t = 4242)]
@@ -27,31 +50,164 @@ pub struct Args {
     /// Identifier default _id
     #[arg(long, default_value_t = String::from("id"))]
     id: String,
+
+    /// How ids are generated for items created without one
+    #[arg(long, value_enum, default_value_t = IdStrategy::Uuid)]
+    id_strategy: IdStrategy,
+
+    /// Suffix of foreign keys used by _embed and _expand, e.g. postId
+    #[arg(long, default_value_t = String::from("Id"))]
+    foreign_key_suffix: String,
+
+    /// When changes are written back to the source file
+    #[arg(long, value_enum, default_value_t = Persist::Always)]
+    persist: Persist,
+
+    /// Quiet period in milliseconds before a debounced write
+    #[arg(long, default_value_t = 500)]
+    persist_delay: u64,
+
+    /// Indentation width on save, reformatting the whole file. By default JSON files keep
+    /// their own layout and only the values that changed are rewritten.
+    #[arg(long)]
+    indent: Option<usize>,
+
+    /// Expand the source as a template: objects carrying "$repeat": n are copied n times and
+    /// placeholders such as "{{name.firstName}}" are replaced by fake data. Never persisted.
+    #[arg(long)]
+    generate: bool,
+
+    /// Seed of every random value (generated ids and data), for reproducible responses
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// JSON Schemas the items of each collection must match, keyed by collection name.
+    /// Defaults to the `<source>.schema.json` file next to the source when there is one.
+    #[arg(long)]
+    schema: Option<String>,
+
+    /// Reload the source whenever it changes on disk
+    #[arg(long)]
+    watch: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the schema of every collection of a source, inferred from its items
+    InferSchema {
+        /// Path of the source file or directory
+        source: String,
+
+        /// Format of the source file, guessed from its extension by default
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+
+        /// Print JSON Schemas or TypeScript interfaces
+        #[arg(long, value_enum, default_value_t = SchemaFormat::JsonSchema)]
+        output: SchemaFormat,
+    },
+}
+
+impl Args {
+    /// Path of the source served, always given when there is no subcommand
+    fn source(&self) -> &str {
+        self.source.as_deref().unwrap_or_default()
+    }
+}
+
+/// Reads `source` like the server would and prints the schemas inferred from it
+fn infer_schema(source: &str, format: Option<Format>, output: SchemaFormat) {
+    let mut source = Source::new(source, format, None, None);
+    let data = source
+        .process()
+        .map_err(jsml_error::JsmlError::from)
+        .and_then(|data| match data {
+            serde_json::Value::Object(data) => Ok(data),
+            _ => Err(jsml_error::JsmlError::bad_request("invalid file content")),
+        });
+    match data {
+        Ok(data) => {
+            // Output piped into e.g. `head` may be cut short, which is not an error
+            let _ = writeln!(std::io::stdout(), "{}", schema::infer_all(&data, output));
+        }
+        Err(e) => {
+            logger::error(&source.path, &e);
+            std::process::exit(1);
+        }
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args = Args::parse();
-    let state = state::State::new(&args)?;
-    let state = web::Data::new(Mutex::new(state));
+    if let Some(Command::InferSchema {
+        source,
+        format,
+        output,
+    }) = &args.command
+    {
+        infer_schema(source, *format, *output);
+        return Ok(());
+    }
+    let state = match state::State::new(&args) {
+        Ok(state) => state,
+        Err(e) => {
+            logger::error(args.source(), &e);
+            std::process::exit(1);
+        }
+    };
+    let state = web::Data::new(RwLock::new(state));
     let Ok(port) = u16::try_from(args.port) else {
         return Err(Error::new(std::io::ErrorKind::InvalidData, "Invalid port"));
     };
+    let _watcher = if args.watch {
+        Some(watcher::watch(args.source(), state.clone()).map_err(Error::other)?)
+    } else {
+        None
+    };
+    state::debounce(state.clone());
 
+    let server_state = state.clone();
     HttpServer::new(move || {
         let cors = Cors::permissive();
         App::new()
             .wrap(middleware::NormalizePath::trim())
             .wrap(cors)
-            .app_data(state.clone())
+            .app_data(server_state.clone())
+            .app_data(web::JsonConfig::default().error_handler(routes::json_error))
+            .service(
+                web::scope("/__admin")
+                    .service(admin::reset)
+                    .service(admin::snapshot)
+                    .service(admin::restore)
+                    .service(admin::db),
+            )
+            .service(openapi::spec)
+            .service(openapi::docs)
+            .service(ws::socket)
+            .service(routes::all_events)
+            .service(routes::route_events)
             .service(routes::get_all)
+            .service(routes::get_children)
             .service(routes::get_one)
             .service(routes::put_one)
+            .service(routes::put_singular)
             .service(routes::post_one)
+            .service(routes::post_child)
             .service(routes::patch_one)
+            .service(routes::patch_all)
             .service(routes::delete)
+            .service(routes::delete_all)
+            .default_service(web::to(routes::not_found))
     })
     .bind(("127.0.0.1", port))?
     .run()
-    .await
+    .await?;
+
+    // Write what a debounced burst left pending before exiting
+    let writer = state.write().close();
+    if let Some(writer) = writer {
+        writer.await.map_err(Error::other)?;
+    }
+    Ok(())
 }
@@ -0,0 +1,443 @@
+use actix_web::{get, http::StatusCode, web, HttpResponse};
+use parking_lot::RwLock;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+
+use crate::{
+    database::{singular, Database, Resource},
+    logger::{LogEntry, RouteEntry},
+    schema,
+    state::State,
+};
+
+/// Swagger UI rendering `/__openapi.json`, loaded from a CDN
+const DOCS: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+    <title>jsml-server</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+    <div id="docs"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>SwaggerUIBundle({ url: "/__openapi.json", dom_id: "#docs" });</script>
+</body>
+</html>"##;
+
+#[get("/__openapi.json")]
+async fn spec(data: web::Data<RwLock<State>>) -> HttpResponse {
+    let data = data.read();
+    let mut log = RouteEntry::new(&format!("GET - localhost:{}/__openapi.json", data.port));
+    log.update(StatusCode::OK);
+    HttpResponse::Ok().json(document(&data.database, data.port))
+}
+
+#[get("/__docs")]
+async fn docs() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html").body(DOCS)
+}
+
+/// Built-in component schemas, prefixed so that no route gets their name
+const ERROR_SCHEMA: &str = "JsmlError";
+const BULK_RESULT_SCHEMA: &str = "JsmlBulkResult";
+
+/// Name of the component schema describing the items of `route`, e.g. `Post` for the
+/// `posts` collection. The name is in PascalCase and keeps only the characters allowed in
+/// an identifier, so `blog-posts` gives `BlogPost`.
+pub fn schema_name(route: &str, collection: bool) -> String {
+    let name = if collection {
+        singular(route)
+    } else {
+        String::from(route)
+    };
+    let mut pascal = String::new();
+    for word in name.split(|c: char| !c.is_alphanumeric()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            pascal.extend(first.to_uppercase().chain(chars));
+        }
+    }
+    match pascal.chars().next() {
+        None => String::from("Resource"),
+        Some(first) if first.is_numeric() => format!("_{pascal}"),
+        Some(_) => pascal,
+    }
+}
+
+/// Names of the schemas describing `routes`, each a route and whether it is a collection.
+/// A name already `reserved` or given to an earlier route gets a number from 2, e.g.
+/// `Post` and `Post2` for a `posts` collection and a `post` singular resource.
+pub fn schema_names<'a>(
+    routes: impl IntoIterator<Item = (&'a str, bool)>,
+    reserved: &[&str],
+) -> Vec<String> {
+    let mut taken: HashSet<String> = reserved.iter().map(|name| name.to_string()).collect();
+    routes
+        .into_iter()
+        .map(|(route, collection)| {
+            let base = schema_name(route, collection);
+            let mut name = base.clone();
+            let mut n = 2;
+            while taken.contains(&name) {
+                name = format!("{base}{n}");
+                n += 1;
+            }
+            taken.insert(name.clone());
+            name
+        })
+        .collect()
+}
+
+/// OpenAPI 3.1 description of every route serving `database`, describing the items with
+/// their declared schema or else one inferred from the current data
+pub fn document(database: &Database, port: usize) -> Value {
+    let mut paths = Map::new();
+    let mut schemas = Map::new();
+    let names = schema_names(
+        database
+            .database
+            .iter()
+            .map(|(route, resource)| (route.as_str(), matches!(resource, Resource::Collection(_)))),
+        &[ERROR_SCHEMA, BULK_RESULT_SCHEMA],
+    );
+    for ((route, resource), name) in database.database.iter().zip(names) {
+        let item = schema_ref(&name);
+        match resource {
+            Resource::Collection(items) => {
+                let inferred = || schema::infer(items.values());
+                let declared = database.schemas.get(route).cloned();
+                schemas.insert(name.clone(), declared.unwrap_or_else(inferred));
+                paths.insert(format!("/{route}"), collection_path(route, &item));
+                paths.insert(
+                    format!("/{route}/{{id}}"),
+                    item_path(route, &item, &database.options.id_key),
+                );
+            }
+            Resource::Singular(value) => {
+                let declared = database.schemas.get(route).cloned();
+                schemas.insert(
+                    name.clone(),
+                    declared.unwrap_or_else(|| schema::infer([value])),
+                );
+                paths.insert(format!("/{route}"), singular_path(route, &item));
+            }
+        }
+    }
+    schemas.insert(
+        String::from(ERROR_SCHEMA),
+        json!({
+            "type": "object",
+            "properties": {
+                "error": {
+                    "type": "object",
+                    "properties": {
+                        "code": { "type": "string" },
+                        "message": { "type": "string" },
+                        "path": { "type": ["string", "null"] },
+                    },
+                    "required": ["code", "message"],
+                },
+            },
+            "required": ["error"],
+        }),
+    );
+    schemas.insert(
+        String::from(BULK_RESULT_SCHEMA),
+        json!({
+            "type": "object",
+            "properties": {
+                "count": { "type": "integer" },
+                "results": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": { "id": { "type": "string" }, "item": {} },
+                        "required": ["id"],
+                    },
+                },
+            },
+            "required": ["count", "results"],
+        }),
+    );
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "jsml-server",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": format!("http://localhost:{port}") }],
+        "paths": paths,
+        "components": { "schemas": schemas },
+    })
+}
+
+fn collection_path(route: &str, item: &Value) -> Value {
+    let list_parameters = json!([
+        query_parameter(
+            "_page",
+            "integer",
+            "Page to return, from 0, adds a Link header"
+        ),
+        query_parameter(
+            "_limit",
+            "integer",
+            "Number of items per page, 10 by default"
+        ),
+        query_parameter("_start", "integer", "Index of the first item to return"),
+        query_parameter("_end", "integer", "Index following the last item to return"),
+        query_parameter("_sort", "string", "Comma separated fields to sort by"),
+        query_parameter(
+            "_order",
+            "string",
+            "Comma separated asc or desc, one per sort field"
+        ),
+        query_parameter("q", "string", "Full-text search, case insensitive"),
+        query_parameter(
+            "_embed",
+            "string",
+            "Child collections to attach to each item"
+        ),
+        query_parameter(
+            "_expand",
+            "string",
+            "Parent resources to attach to each item"
+        ),
+    ]);
+    let filter_description = "Items can also be filtered by any field, e.g. `title=x`, with \
+        the `_ne`, `_gt`, `_gte`, `_lt`, `_lte`, `_like` and `_in` suffixes";
+    json!({
+        "get": {
+            "tags": [route],
+            "summary": format!("List {route}"),
+            "description": filter_description,
+            "parameters": list_parameters,
+            "responses": {
+                "200": {
+                    "description": "Matching items",
+                    "headers": {
+                        "X-Total-Count": {
+                            "description": "Number of matching items before pagination",
+                            "schema": { "type": "integer" },
+                        },
+                        "Link": {
+                            "description": "First, previous, next and last pages",
+                            "schema": { "type": "string" },
+                        },
+                    },
+                    "content": json_content(&json!({ "type": "array", "items": item })),
+                },
+                "404": error_response("Unknown collection"),
+            },
+        },
+        "post": {
+            "tags": [route],
+            "summary": format!("Create one or several {route}"),
+            "description": "An array body creates every item or none of them",
+            "requestBody": {
+                "required": true,
+                "content": json_content(&json!({
+                    "oneOf": [item, { "type": "array", "items": item }],
+                })),
+            },
+            "responses": {
+                "201": {
+                    "description": "Created item, or a bulk result for an array body",
+                    "headers": {
+                        "Location": {
+                            "description": "URL of the created item",
+                            "schema": { "type": "string" },
+                        },
+                    },
+                    "content": json_content(&json!({
+                        "oneOf": [item, schema_ref(BULK_RESULT_SCHEMA)],
+                    })),
+                },
+                "400": error_response("Invalid body"),
+                "409": error_response("Duplicate id"),
+            },
+        },
+        "patch": {
+            "tags": [route],
+            "summary": format!("Update every matching item of {route}"),
+            "description": format!(
+                "{filter_description}. A filter or `q` is required and the body cannot set the id."
+            ),
+            "requestBody": { "required": true, "content": json_content(&json!({ "type": "object" })) },
+            "responses": {
+                "200": bulk_response("Updated items"),
+                "400": error_response("Invalid body or no filter given"),
+            },
+        },
+        "delete": {
+            "tags": [route],
+            "summary": format!("Delete every matching item of {route}"),
+            "description": format!("{filter_description}. A filter or `q` is required."),
+            "responses": {
+                "200": bulk_response("Ids of the deleted items"),
+                "400": error_response("No filter given"),
+            },
+        },
+    })
+}
+
+fn item_path(route: &str, item: &Value, id_key: &str) -> Value {
+    let parameters = json!([{
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "description": format!("Value of the `{id_key}` field"),
+        "schema": { "type": "string" },
+    }]);
+    json!({
+        "parameters": parameters,
+        "get": {
+            "tags": [route],
+            "summary": format!("Get one of {route}"),
+            "parameters": [
+                query_parameter("_embed", "string", "Child collections to attach"),
+                query_parameter("_expand", "string", "Parent resources to attach"),
+            ],
+            "responses": {
+                "200": { "description": "The item", "content": json_content(item) },
+                "404": error_response("Not found"),
+            },
+        },
+        "put": {
+            "tags": [route],
+            "summary": format!("Replace one of {route}"),
+            "requestBody": { "required": true, "content": json_content(item) },
+            "responses": {
+                "200": { "description": "The replaced item", "content": json_content(item) },
+                "400": error_response("Invalid body"),
+                "404": error_response("Not found"),
+            },
+        },
+        "patch": {
+            "tags": [route],
+            "summary": format!("Update fields of one of {route}"),
+            "requestBody": { "required": true, "content": json_content(&json!({ "type": "object" })) },
+            "responses": {
+                "200": { "description": "The updated item", "content": json_content(item) },
+                "400": error_response("Invalid body"),
+                "404": error_response("Not found"),
+            },
+        },
+        "delete": {
+            "tags": [route],
+            "summary": format!("Delete one of {route}"),
+            "responses": {
+                "204": { "description": "Deleted" },
+                "404": error_response("Not found"),
+            },
+        },
+    })
+}
+
+fn singular_path(route: &str, item: &Value) -> Value {
+    json!({
+        "get": {
+            "tags": [route],
+            "summary": format!("Get {route}"),
+            "responses": { "200": { "description": "The resource", "content": json_content(item) } },
+        },
+        "put": {
+            "tags": [route],
+            "summary": format!("Replace {route}"),
+            "requestBody": { "required": true, "content": json_content(item) },
+            "responses": {
+                "200": { "description": "The replaced resource", "content": json_content(item) },
+                "400": error_response("Invalid body"),
+            },
+        },
+        "patch": {
+            "tags": [route],
+            "summary": format!("Update fields of {route}"),
+            "requestBody": { "required": true, "content": json_content(&json!({ "type": "object" })) },
+            "responses": {
+                "200": { "description": "The updated resource", "content": json_content(item) },
+                "400": error_response("Invalid body"),
+            },
+        },
+    })
+}
+
+fn query_parameter(name: &str, kind: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": { "type": kind },
+    })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{name}") })
+}
+
+fn json_content(schema: &Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": json_content(&schema_ref(ERROR_SCHEMA)),
+    })
+}
+
+fn bulk_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": json_content(&schema_ref(BULK_RESULT_SCHEMA)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{IdStrategy, Options};
+    use crate::random::Random;
+
+    #[test]
+    fn component_names_never_collide() {
+        let options = Options {
+            id_key: String::from("id"),
+            id_strategy: IdStrategy::Increment,
+            assign_ids: false,
+            random: Random::new(Some(1)),
+            foreign_key_suffix: String::from("Id"),
+            schemas: Value::Null,
+        };
+        let data = json!({
+            "posts": [{ "id": 1 }],
+            "post": { "title": "draft" },
+            "errors": [{ "id": 1 }],
+            "bulkResults": [{ "id": 1 }],
+            "jsmlErrors": [{ "id": 1 }],
+        });
+        let database = Database::new(&options, &data).expect("valid database");
+        let document = document(&database, 3000);
+        let schemas = document["components"]["schemas"]
+            .as_object()
+            .expect("component schemas");
+        let names: Vec<&str> = schemas.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Post",
+                "Post2",
+                "Error",
+                "BulkResult",
+                "JsmlError2",
+                "JsmlError",
+                "JsmlBulkResult",
+            ]
+        );
+        assert_eq!(
+            document["paths"]["/post"]["get"]["responses"]["200"]["content"]["application/json"]
+                ["schema"]["$ref"],
+            json!("#/components/schemas/Post2")
+        );
+    }
+}
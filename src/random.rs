@@ -0,0 +1,58 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use uuid::Uuid;
+
+/// Reference time of seeded runs (2024-01-01T00:00:00Z), so generated dates are reproducible too
+const SEEDED_NOW: i64 = 1_704_067_200;
+
+/// Source of every random value the server produces. Seeded by `--seed`, it makes ids and
+/// generated data identical from one run to the next.
+#[derive(Debug, Clone)]
+pub struct Random {
+    rng: Arc<Mutex<StdRng>>,
+    seeded: bool,
+}
+
+impl Random {
+    pub fn new(seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            rng: Arc::new(Mutex::new(rng)),
+            seeded: seed.is_some(),
+        }
+    }
+
+    /// Copy of the state of the generator, to come back to with `restore`
+    pub fn save(&self) -> StdRng {
+        self.lock().clone()
+    }
+
+    pub fn restore(&self, state: StdRng) {
+        *self.lock() = state;
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, StdRng> {
+        self.rng.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Seconds since the epoch, fixed when seeded
+    pub fn now(&self) -> i64 {
+        if self.seeded {
+            return SEEDED_NOW;
+        }
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i64)
+    }
+}
+
+pub fn uuid(rng: &mut impl Rng) -> Uuid {
+    uuid::Builder::from_random_bytes(rng.gen()).into_uuid()
+}
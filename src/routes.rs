@@ -1,103 +1,405 @@
-use actix_web::{delete, get, http::StatusCode, patch, post, put, web, HttpResponse, Responder};
-use serde_json::Value;
-use std::{collections::HashMap, sync::Mutex};
+use actix_web::{
+    delete,
+    error::JsonPayloadError,
+    get,
+    http::{header, header::ContentType, StatusCode},
+    patch, post, put, web, HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError,
+};
+use futures_util::{future::ready, stream, StreamExt};
+use parking_lot::RwLock;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, convert::Infallible};
+use tokio::sync::broadcast::error::RecvError;
 
 use crate::{
+    jsml_error::JsmlError,
     logger::{LogEntry, RouteEntry},
     state::State,
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Like,
+    In,
+}
+
+impl Operator {
+    const SUFFIXES: [(&'static str, Self); 7] = [
+        ("_ne", Self::Ne),
+        ("_gt", Self::Gt),
+        ("_gte", Self::Gte),
+        ("_lt", Self::Lt),
+        ("_lte", Self::Lte),
+        ("_like", Self::Like),
+        ("_in", Self::In),
+    ];
+
+    /// Splits `total_gte` into (`total`, `Gte`), falling back to equality
+    fn parse(key: &str) -> (&str, Self) {
+        for (suffix, operator) in Self::SUFFIXES {
+            if let Some(field) = key.strip_suffix(suffix) {
+                if !field.is_empty() {
+                    return (field, operator);
+                }
+            }
+        }
+        (key, Self::Eq)
+    }
+}
+
+#[derive(Debug)]
+pub struct Filter {
+    pub field: String,
+    pub operator: Operator,
+    pub values: Vec<String>,
+    pub patterns: Vec<Regex>,
+}
+
+impl Filter {
+    pub fn equals(field: &str, value: &str) -> Self {
+        Self {
+            field: String::from(field),
+            operator: Operator::Eq,
+            values: vec![String::from(value)],
+            patterns: vec![],
+        }
+    }
+
+    fn new(key: &str, value: &str) -> Self {
+        let (field, operator) = Operator::parse(key);
+        let values: Vec<String> = value.split(',').map(String::from).collect();
+        let patterns = match operator {
+            Operator::Like => values
+                .iter()
+                .map(|value| {
+                    Regex::new(&format!("(?i){value}"))
+                        .or_else(|_| Regex::new(&format!("(?i){}", regex::escape(value))))
+                        .expect("escaped pattern is always valid")
+                })
+                .collect(),
+            _ => vec![],
+        };
+        Self {
+            field: String::from(field),
+            operator,
+            values,
+            patterns,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug)]
+pub struct Sort {
+    pub field: String,
+    pub order: Order,
+}
+
 #[derive(Default, Debug)]
 pub struct QueryParams {
     pub page: Option<usize>,
     pub limit: Option<usize>,
-    pub filters: HashMap<String, Vec<String>>,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+    pub sort: Vec<Sort>,
+    pub search: Option<String>,
+    pub embed: Vec<String>,
+    pub expand: Vec<String>,
+    pub filters: Vec<Filter>,
 }
 
 impl QueryParams {
-    fn new(query: HashMap<String, String>) -> Self {
+    /// Number of items per page when `_page` is given without `_limit`
+    pub fn page_size(&self) -> usize {
+        self.limit.unwrap_or(10)
+    }
+
+    /// Whether the query narrows down the items with filters or a search
+    pub fn is_filtered(&self) -> bool {
+        !self.filters.is_empty() || self.search.is_some()
+    }
+
+    pub fn new(query: HashMap<String, String>) -> Self {
         let mut res = Self::default();
         query.keys().for_each(|key| match key.as_str() {
             "_page" => {
-                if let Ok(page) = query[key].parse::<usize>() {
-                    res.page = Some(page);
-                } else {
-                    res.page = None;
-                }
+                res.page = query[key].parse::<usize>().ok();
             }
             "_limit" => {
-                if let Ok(limit) = query[key].parse::<usize>() {
-                    res.limit = Some(limit);
-                } else {
-                    res.limit = None;
-                }
+                res.limit = query[key].parse::<usize>().ok();
+            }
+            "_start" => {
+                res.start = query[key].parse::<usize>().ok();
             }
+            "_end" => {
+                res.end = query[key].parse::<usize>().ok();
+            }
+            "q" => {
+                res.search = Some(query[key].to_lowercase()).filter(|term| !term.is_empty());
+            }
+            "_embed" => {
+                res.embed = query[key].split(',').map(String::from).collect();
+            }
+            "_expand" => {
+                res.expand = query[key].split(',').map(String::from).collect();
+            }
+            "_sort" | "_order" => {}
             _ => {
-                let values: Vec<String> = query[key].split(',').map(String::from).collect();
-                res.filters.insert(key.clone(), values);
+                res.filters.push(Filter::new(key, &query[key]));
             }
         });
+        if let Some(fields) = query.get("_sort") {
+            let orders: Vec<&str> = query
+                .get("_order")
+                .map_or(vec![], |o| o.split(',').collect());
+            res.sort = fields
+                .split(',')
+                .filter(|field| !field.is_empty())
+                .enumerate()
+                .map(|(i, field)| Sort {
+                    field: String::from(field),
+                    order: match orders.get(i) {
+                        Some(order) if order.eq_ignore_ascii_case("desc") => Order::Desc,
+                        _ => Order::Asc,
+                    },
+                })
+                .collect();
+        }
         res
     }
 }
 
+/// Builds an RFC 5988 `Link` header pointing at the first, previous, next and last pages
+fn pagination_links(req: &HttpRequest, page: usize, limit: usize, total: usize) -> String {
+    let info = req.connection_info();
+    let base = format!("{}://{}{}", info.scheme(), info.host(), req.path());
+    let params: Vec<&str> = req
+        .query_string()
+        .split('&')
+        .filter(|param| !param.is_empty() && !param.starts_with("_page="))
+        .collect();
+    let link = |page: usize, rel: &str| {
+        let mut query = params.clone();
+        let page = format!("_page={page}");
+        query.push(&page);
+        format!("<{base}?{}>; rel=\"{rel}\"", query.join("&"))
+    };
+    let last = total.saturating_sub(1) / limit.max(1);
+    let mut links = vec![link(0, "first")];
+    if page > 0 {
+        links.push(link(page.min(last + 1) - 1, "prev"));
+    }
+    if page < last {
+        links.push(link(page + 1, "next"));
+    }
+    links.push(link(last, "last"));
+    links.join(", ")
+}
+/// Serializes a page of items along with its `X-Total-Count` and `Link` headers
+fn list_response(
+    req: &HttpRequest,
+    query: &QueryParams,
+    items: &[Value],
+    total: usize,
+) -> HttpResponse {
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header(("X-Total-Count", total.to_string()));
+    if let Some(page) = query.page {
+        let links = pagination_links(req, page, query.page_size(), total);
+        builder.insert_header((header::LINK, links));
+    }
+    pretty_json(builder, items)
+}
+
+/// Indents read responses, serializing a `Value` cannot fail
+fn pretty_json(mut builder: HttpResponseBuilder, body: &(impl Serialize + ?Sized)) -> HttpResponse {
+    let body = serde_json::to_string_pretty(body).unwrap_or_default();
+    builder.content_type(ContentType::json()).body(body)
+}
+
+/// Responds 201 with the created item, its URL in the `Location` header
+fn created_response(route: &str, id: Option<String>, item: &Value) -> HttpResponse {
+    let mut builder = HttpResponse::Created();
+    if let Some(id) = id {
+        builder.insert_header((header::LOCATION, format!("/{route}/{id}")));
+    }
+    builder.json(item)
+}
+
+/// Reports the outcome of a bulk request, item by item
+fn bulk_results(data: &State, items: &[Value]) -> Value {
+    let results: Vec<Value> = items
+        .iter()
+        .map(|item| json!({ "id": data.item_id(item), "item": item }))
+        .collect();
+    json!({ "count": items.len(), "results": results })
+}
+
+/// Rejects malformed JSON bodies with the same error body as every other failure
+pub fn json_error(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    JsmlError::bad_request(&err.to_string())
+        .at(req.path())
+        .into()
+}
+
+/// Answers requests matching no route
+pub async fn not_found(req: HttpRequest) -> Result<HttpResponse, JsmlError> {
+    Err(JsmlError::not_found("route not found").at(req.path()))
+}
+
+/// Streams the changes made to the database as Server-Sent Events, replaying the ones
+/// following `Last-Event-ID` when the client resumes
+fn event_stream(req: &HttpRequest, data: &RwLock<State>, route: Option<String>) -> HttpResponse {
+    let last_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.trim().parse().ok());
+    let data = data.read();
+    let mut log = RouteEntry::new(&format!("GET - localhost:{}{}", data.port, req.path()));
+    let (missed, receiver) = data.subscribe(last_id);
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(event) => Some((event, receiver)),
+            // A client lagging behind is disconnected so that it resumes from the history
+            Err(RecvError::Lagged(_) | RecvError::Closed) => None,
+        }
+    });
+    let body = stream::iter(missed)
+        .chain(live)
+        .filter(move |event| {
+            ready(
+                route
+                    .as_ref()
+                    .is_none_or(|route| *route == event.collection),
+            )
+        })
+        .map(|event| Ok::<_, Infallible>(web::Bytes::from(event.to_sse())));
+    log.update(StatusCode::OK);
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .content_type("text/event-stream")
+        .streaming(body)
+}
+
+#[get("/_events")]
+async fn all_events(req: HttpRequest, data: web::Data<RwLock<State>>) -> HttpResponse {
+    event_stream(&req, &data, None)
+}
+
+#[get("/{route}/_events")]
+async fn route_events(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<RwLock<State>>,
+) -> HttpResponse {
+    event_stream(&req, &data, Some(path.into_inner()))
+}
+
 #[get("/{route}")]
 async fn get_all(
+    req: HttpRequest,
     path: web::Path<String>,
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<Mutex<State>>,
-) -> impl Responder {
+    data: web::Data<RwLock<State>>,
+) -> Result<HttpResponse, JsmlError> {
     let route = path.into_inner();
-    let Ok(data) = data.lock() else {
-        return HttpResponse::InternalServerError().body("Internal Server Error");
-    };
+    let data = data.read();
+    let mut log = RouteEntry::new(&format!("GET - localhost:{}/{route}", data.port));
+    if let Ok(resource) = data.get_singular(&route) {
+        log.update(StatusCode::OK);
+        return Ok(HttpResponse::Ok().json(resource));
+    }
     let query = QueryParams::new(query.into_inner());
     let result = data.query(&route, &query);
-    let mut log = RouteEntry::new(&format!("GET - localhost:{}/{route}", data.port));
     match result {
-        Ok(response) => {
+        Ok(page) => {
             log.update(StatusCode::OK);
             // // data.log(Arc::new(log));
-            if let Ok(response) = serde_json::to_string_pretty(&response) {
-                HttpResponse::Ok().body(response)
-            } else {
-                HttpResponse::InternalServerError().body("Internal Server Error")
-            }
+            let items: Vec<Value> = page
+                .items
+                .iter()
+                .map(|item| data.embed(&route, item, &query))
+                .collect();
+            Ok(list_response(&req, &query, &items, page.total))
         }
         Err(e) => {
-            log.update(StatusCode::NOT_FOUND);
+            log.update(e.status_code());
             // // data.log(log);
-            HttpResponse::NotFound().body(format!("Error: {e}"))
+            Err(e.at(req.path()))
+        }
+    }
+}
+
+#[get("/{parent}/{id}/{child}")]
+async fn get_children(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<RwLock<State>>,
+) -> Result<HttpResponse, JsmlError> {
+    let (parent, id, child) = path.into_inner();
+    let data = data.read();
+    let mut query = QueryParams::new(query.into_inner());
+    let result = data.query_children(&parent, &id, &child, &mut query);
+    let mut log = RouteEntry::new(&format!(
+        "GET - localhost:{}/{parent}/{id}/{child}",
+        data.port
+    ));
+    match result {
+        Ok(page) => {
+            log.update(StatusCode::OK);
+            let items: Vec<Value> = page
+                .items
+                .iter()
+                .map(|item| data.embed(&child, item, &query))
+                .collect();
+            Ok(list_response(&req, &query, &items, page.total))
+        }
+        Err(e) => {
+            log.update(e.status_code());
+            Err(e.at(req.path()))
         }
     }
 }
 
 #[get("/{route}/{id}")]
 async fn get_one(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
-    data: web::Data<Mutex<State>>,
-) -> impl Responder {
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<RwLock<State>>,
+) -> Result<HttpResponse, JsmlError> {
     let (route, id) = path.into_inner();
-    let Ok(data) = data.lock() else {
-        return HttpResponse::InternalServerError().body("Internal Server Error");
-    };
+    let data = data.read();
+    let query = QueryParams::new(query.into_inner());
     let mut log = RouteEntry::new(&format!("GET - localhost:{}/{route}/{id}", data.port));
-    let result = data.get(&route, &id).cloned();
+    let result = data
+        .get(&route, &id)
+        .map(|item| data.embed(&route, item, &query));
     match result {
         Ok(response) => {
             // // data.log(Arc::new(log));
-            if let Ok(response) = serde_json::to_string_pretty(&response) {
-                log.update(StatusCode::OK);
-                HttpResponse::Ok().body(response)
-            } else {
-                log.update(StatusCode::INTERNAL_SERVER_ERROR);
-                HttpResponse::InternalServerError().body("Internal Server Error")
-            }
+            log.update(StatusCode::OK);
+            Ok(pretty_json(HttpResponse::Ok(), &response))
         }
         Err(e) => {
             // // data.log(Arc::new(log));
-            log.update(StatusCode::NOT_FOUND);
-            HttpResponse::NotFound().body(format!("Error: {e}"))
+            log.update(e.status_code());
+            Err(e.at(req.path()))
         }
     }
 }
@@ -105,103 +407,317 @@ async fn get_one(
 // TODO: reduce unnecessary alloc in mutation requests
 #[put("/{route}/{id}")]
 async fn put_one(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
-    data: web::Data<Mutex<State>>,
+    data: web::Data<RwLock<State>>,
     body: web::Json<Value>,
-) -> impl Responder {
+) -> Result<HttpResponse, JsmlError> {
     let (route, id) = path.into_inner();
-    let Ok(mut data) = data.lock() else {
-        return HttpResponse::InternalServerError().body("Internal Server Error");
-    };
+    let mut data = data.write();
     let mut log = RouteEntry::new(&format!("localhost:{}/{route}/{id}", data.port));
-    let result = data.put(&route, &id, &body, false);
+    let result = data.put(&route, &id, &body);
     match result {
         Ok(response) => {
             log.update(StatusCode::OK);
             // // data.log(&log);
-            HttpResponse::Ok().json(response)
+            Ok(HttpResponse::Ok().json(response))
         }
         Err(e) => {
-            log.update(StatusCode::NOT_FOUND);
+            log.update(e.status_code());
             // // data.log(&log);
-            HttpResponse::NotFound().body(format!("Error: {e}"))
+            Err(e.at(req.path()))
+        }
+    }
+}
+
+#[put("/{route}")]
+async fn put_singular(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<RwLock<State>>,
+    body: web::Json<Value>,
+) -> Result<HttpResponse, JsmlError> {
+    let route = path.into_inner();
+    let mut data = data.write();
+    let mut log = RouteEntry::new(&format!("PUT - localhost:{}/{route}", data.port));
+    let result = data.put_singular(&route, &body);
+    match result {
+        Ok(response) => {
+            log.update(StatusCode::OK);
+            Ok(HttpResponse::Ok().json(response))
+        }
+        Err(e) => {
+            log.update(e.status_code());
+            Err(e.at(req.path()))
         }
     }
 }
 
 #[patch("/{route}/{id}")]
 async fn patch_one(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
-    data: web::Data<Mutex<State>>,
+    data: web::Data<RwLock<State>>,
     body: web::Json<Value>,
-) -> impl Responder {
+) -> Result<HttpResponse, JsmlError> {
     let (route, id) = path.into_inner();
-    let Ok(mut data) = data.lock() else {
-        return HttpResponse::InternalServerError().body("Internal Server Error");
-    };
+    let mut data = data.write();
     let mut log = RouteEntry::new(&format!("PATCH - localhost:{}/{route}/{id}", data.port));
-    let result = data.patch(&route, &id, &body, false);
+    let result = data.patch(&route, &id, &body);
     match result {
         Ok(response) => {
             log.update(StatusCode::OK);
             // data.log(&log);
-            HttpResponse::Ok().json(response)
+            Ok(HttpResponse::Ok().json(response))
         }
         Err(e) => {
-            log.update(StatusCode::NOT_FOUND);
+            log.update(e.status_code());
             // data.log(&log);
-            HttpResponse::NotFound().body(format!("Error: {e}"))
+            Err(e.at(req.path()))
+        }
+    }
+}
+
+#[patch("/{route}")]
+async fn patch_all(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<RwLock<State>>,
+    body: web::Json<Value>,
+) -> Result<HttpResponse, JsmlError> {
+    let route = path.into_inner();
+    let mut data = data.write();
+    let mut log = RouteEntry::new(&format!("PATCH - localhost:{}/{route}", data.port));
+    let query = QueryParams::new(query.into_inner());
+    let result = if data.get_singular(&route).is_ok() {
+        data.patch_singular(&route, &body)
+    } else if query.is_filtered() {
+        data.patch_many(&route, &query, &body)
+            .map(|items| bulk_results(&data, &items))
+    } else {
+        Err(JsmlError::bad_request(
+            "updating several items requires a filter or q",
+        ))
+    };
+    match result {
+        Ok(response) => {
+            log.update(StatusCode::OK);
+            Ok(HttpResponse::Ok().json(response))
+        }
+        Err(e) => {
+            log.update(e.status_code());
+            Err(e.at(req.path()))
         }
     }
 }
 
 #[post("/{route}")]
 async fn post_one(
+    req: HttpRequest,
     path: web::Path<String>,
-    data: web::Data<Mutex<State>>,
+    data: web::Data<RwLock<State>>,
     body: web::Json<Value>,
-) -> impl Responder {
+) -> Result<HttpResponse, JsmlError> {
     let route = path.into_inner();
-    let Ok(mut data) = data.lock() else {
-        return HttpResponse::InternalServerError().body("Internal Server Error");
-    };
+    let mut data = data.write();
     let mut log = RouteEntry::new(&format!("POST - localhost:{}/{route}", data.port));
-    let result = data.post(&route, &body, true);
+    if data.get_singular(&route).is_ok() {
+        let e = JsmlError::method_not_allowed(&format!(
+            "POST is not supported on the singular resource {route}, use PUT or PATCH"
+        ));
+        log.update(e.status_code());
+        return Err(e.at(req.path()));
+    }
+    if let Some(bodies) = body.as_array() {
+        let result = data.post_many(&route, bodies);
+        return match result {
+            Ok(items) => {
+                log.update(StatusCode::CREATED);
+                Ok(HttpResponse::Created().json(bulk_results(&data, &items)))
+            }
+            Err(e) => {
+                log.update(e.status_code());
+                Err(e.at(req.path()))
+            }
+        };
+    }
+    let result = data.post(&route, &body);
     match result {
         Ok(response) => {
-            log.update(StatusCode::OK);
+            log.update(StatusCode::CREATED);
             // data.log(&log);
-            HttpResponse::Ok().json(response)
+            let id = data.item_id(&response);
+            Ok(created_response(&route, id, &response))
         }
         Err(e) => {
-            log.update(StatusCode::NOT_FOUND);
+            log.update(e.status_code());
             // data.log(&log);
-            HttpResponse::NotFound().body(format!("Error: {e}"))
+            Err(e.at(req.path()))
+        }
+    }
+}
+
+#[post("/{parent}/{id}/{child}")]
+async fn post_child(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    data: web::Data<RwLock<State>>,
+    body: web::Json<Value>,
+) -> Result<HttpResponse, JsmlError> {
+    let (parent, id, child) = path.into_inner();
+    let mut data = data.write();
+    let mut log = RouteEntry::new(&format!(
+        "POST - localhost:{}/{parent}/{id}/{child}",
+        data.port
+    ));
+    let result = data.post_child(&parent, &id, &child, &body);
+    match result {
+        Ok(response) => {
+            log.update(StatusCode::CREATED);
+            let id = data.item_id(&response);
+            Ok(created_response(&child, id, &response))
+        }
+        Err(e) => {
+            log.update(e.status_code());
+            Err(e.at(req.path()))
         }
     }
 }
 
 #[delete("/{route}/{id}")]
 async fn delete(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
-    data: web::Data<Mutex<State>>,
-) -> impl Responder {
+    data: web::Data<RwLock<State>>,
+) -> Result<HttpResponse, JsmlError> {
     let (route, id) = path.into_inner();
-    let Ok(mut data) = data.lock() else {
-        return HttpResponse::InternalServerError().body("Internal Server Error");
-    };
+    let mut data = data.write();
     let mut log = RouteEntry::new(&format!("DELETE - localhost:{}/{route}/{id}", data.port));
-    let result = data.delete(&route, &id, false);
+    let result = data.delete(&route, &id);
     match result {
-        Ok(response) => {
-            log.update(StatusCode::OK);
+        Ok(()) => {
+            log.update(StatusCode::NO_CONTENT);
             // data.log(&log);
-            HttpResponse::Ok().json(response)
+            Ok(HttpResponse::NoContent().finish())
         }
         Err(e) => {
-            log.update(StatusCode::NOT_FOUND);
+            log.update(e.status_code());
             // data.log(&log);
-            HttpResponse::NotFound().body(format!("Error: {e}"))
+            Err(e.at(req.path()))
         }
     }
 }
+
+/// Deletes the items matching the query filters, refusing to empty a collection by mistake
+#[delete("/{route}")]
+async fn delete_all(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<RwLock<State>>,
+) -> Result<HttpResponse, JsmlError> {
+    let route = path.into_inner();
+    let query = QueryParams::new(query.into_inner());
+    let mut data = data.write();
+    let mut log = RouteEntry::new(&format!("DELETE - localhost:{}/{route}", data.port));
+    let result = if data.get_singular(&route).is_ok() {
+        Err(JsmlError::method_not_allowed(&format!(
+            "DELETE is not supported on the singular resource {route}"
+        )))
+    } else if query.is_filtered() {
+        data.delete_many(&route, &query)
+    } else {
+        Err(JsmlError::bad_request(
+            "deleting several items requires a filter or q",
+        ))
+    };
+    match result {
+        Ok(ids) => {
+            log.update(StatusCode::OK);
+            let results: Vec<Value> = ids.iter().map(|id| json!({ "id": id })).collect();
+            Ok(HttpResponse::Ok().json(json!({ "count": ids.len(), "results": results })))
+        }
+        Err(e) => {
+            log.update(e.status_code());
+            Err(e.at(req.path()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> QueryParams {
+        QueryParams::new(
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn operator_parse_splits_suffix() {
+        assert_eq!(Operator::parse("total_gte"), ("total", Operator::Gte));
+        assert_eq!(Operator::parse("total_gt"), ("total", Operator::Gt));
+        assert_eq!(Operator::parse("total_lte"), ("total", Operator::Lte));
+        assert_eq!(Operator::parse("title_like"), ("title", Operator::Like));
+        assert_eq!(Operator::parse("id_in"), ("id", Operator::In));
+        assert_eq!(
+            Operator::parse("author.name_ne"),
+            ("author.name", Operator::Ne)
+        );
+    }
+
+    #[test]
+    fn operator_parse_falls_back_to_equality() {
+        assert_eq!(Operator::parse("title"), ("title", Operator::Eq));
+        assert_eq!(Operator::parse("_gte"), ("_gte", Operator::Eq));
+        assert_eq!(Operator::parse("in"), ("in", Operator::Eq));
+    }
+
+    #[test]
+    fn filter_splits_values_and_escapes_invalid_patterns() {
+        let filter = Filter::new("id_in", "1,3");
+        assert_eq!(filter.values, vec!["1", "3"]);
+        let filter = Filter::new("title_like", "(a");
+        assert!(filter.patterns[0].is_match("x(A"));
+        assert!(!filter.patterns[0].is_match("a"));
+        assert_eq!(
+            params(&[("title_ne", "a")]).filters[0].operator,
+            Operator::Ne
+        );
+    }
+
+    #[test]
+    fn query_params_read_sort_orders() {
+        let query = params(&[("_sort", "views,title"), ("_order", "DESC")]);
+        let sort: Vec<(&str, Order)> = query
+            .sort
+            .iter()
+            .map(|sort| (sort.field.as_str(), sort.order))
+            .collect();
+        assert_eq!(sort, vec![("views", Order::Desc), ("title", Order::Asc)]);
+        assert!(query.filters.is_empty());
+    }
+
+    #[test]
+    fn query_params_ignore_bad_numbers() {
+        let query = params(&[("_page", "x"), ("_start", "-1"), ("_limit", "5")]);
+        assert_eq!(query.page, None);
+        assert_eq!(query.start, None);
+        assert_eq!(query.limit, Some(5));
+        assert_eq!(query.page_size(), 5);
+        assert_eq!(params(&[]).page_size(), 10);
+    }
+
+    #[test]
+    fn query_params_lowercase_search_and_ignore_empty_one() {
+        assert_eq!(params(&[("q", "Hello")]).search.as_deref(), Some("hello"));
+        let query = params(&[("q", "")]);
+        assert_eq!(query.search, None);
+        assert!(query.filters.is_empty());
+    }
+}
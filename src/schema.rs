@@ -0,0 +1,321 @@
+use clap::ValueEnum;
+use indexmap::IndexMap;
+use serde_json::{json, Map, Value};
+
+use crate::{database::KEPT_KEYS, openapi::schema_names};
+
+/// What the values seen at one place of the documents have in common
+#[derive(Default)]
+struct Shape {
+    /// JSON Schema type names, in the order they were first seen
+    types: Vec<&'static str>,
+    /// Number of objects seen, to tell required properties from optional ones
+    objects: usize,
+    /// Properties of the objects seen, with how many objects held each of them
+    properties: IndexMap<String, (Shape, usize)>,
+    /// Shape of the elements of the arrays seen
+    items: Option<Box<Shape>>,
+}
+
+impl Shape {
+    fn add(&mut self, value: &Value) {
+        let kind = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(number) if number.is_f64() => "number",
+            Value::Number(_) => "integer",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        if !self.types.contains(&kind) {
+            self.types.push(kind);
+        }
+        match value {
+            Value::Object(map) => {
+                self.objects += 1;
+                for (key, value) in map {
+                    let (shape, count) = self.properties.entry(key.clone()).or_default();
+                    shape.add(value);
+                    *count += 1;
+                }
+            }
+            Value::Array(items) => {
+                let shape = self.items.get_or_insert_with(Box::default);
+                for item in items {
+                    shape.add(item);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn to_schema(&self) -> Value {
+        let mut schema = Map::new();
+        // Integers are numbers too, keep the wider type when both were seen
+        let types: Vec<&str> = self
+            .types
+            .iter()
+            .filter(|kind| **kind != "integer" || !self.types.contains(&"number"))
+            .copied()
+            .collect();
+        match types.as_slice() {
+            [] => {}
+            [kind] => {
+                schema.insert(String::from("type"), json!(kind));
+            }
+            kinds => {
+                schema.insert(String::from("type"), json!(kinds));
+            }
+        }
+        if self.objects > 0 {
+            let mut properties = Map::new();
+            let mut required = vec![];
+            for (key, (shape, count)) in &self.properties {
+                properties.insert(key.clone(), shape.to_schema());
+                if *count == self.objects {
+                    required.push(key.clone());
+                }
+            }
+            schema.insert(String::from("properties"), Value::Object(properties));
+            if !required.is_empty() {
+                schema.insert(String::from("required"), json!(required));
+            }
+        }
+        if let Some(items) = &self.items {
+            schema.insert(String::from("items"), items.to_schema());
+        }
+        Value::Object(schema)
+    }
+}
+
+/// Infers the JSON Schema that every one of `values` satisfies: a property is required
+/// when all the objects hold it, and a field seen with several types accepts each of them
+pub fn infer<'a>(values: impl IntoIterator<Item = &'a Value>) -> Value {
+    let mut shape = Shape::default();
+    for value in values {
+        shape.add(value);
+    }
+    shape.to_schema()
+}
+
+/// Global TypeScript types that an interface named after a route would shadow
+const TYPESCRIPT_GLOBALS: &[&str] = &[
+    "Array", "Boolean", "Date", "Error", "Function", "Iterator", "JSON", "Map", "Math", "Number",
+    "Object", "Omit", "Partial", "Pick", "Promise", "Readonly", "Record", "RegExp", "Required",
+    "Set", "String", "Symbol",
+];
+
+/// How `infer-schema` prints the inferred schemas
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SchemaFormat {
+    /// One JSON Schema per collection, keyed by collection name, as read by `--schema`
+    JsonSchema,
+    /// One TypeScript interface per collection
+    Typescript,
+}
+
+/// Schemas of every collection and singular resource of the source `data`, inferred from
+/// their items. Items need no id, so any source can be described before it is served.
+pub fn infer_all(data: &Map<String, Value>, format: SchemaFormat) -> String {
+    let routes: Vec<(&String, &Value)> = data
+        .iter()
+        .filter(|(route, _)| !KEPT_KEYS.contains(&route.as_str()))
+        .collect();
+    let names = schema_names(
+        routes
+            .iter()
+            .map(|(route, value)| (route.as_str(), value.is_array())),
+        TYPESCRIPT_GLOBALS,
+    );
+    let schemas = routes
+        .into_iter()
+        .zip(names)
+        .map(|((route, value), name)| match value {
+            Value::Array(items) => (route, name, infer(items)),
+            value => (route, name, infer([value])),
+        });
+    match format {
+        SchemaFormat::JsonSchema => {
+            let schemas: Map<String, Value> = schemas
+                .map(|(route, _, schema)| (route.clone(), schema))
+                .collect();
+            serde_json::to_string_pretty(&schemas).unwrap_or_default()
+        }
+        SchemaFormat::Typescript => {
+            let declarations: Vec<String> = schemas
+                .map(|(_, name, schema)| match schema["type"].as_str() {
+                    Some("object") => {
+                        format!("export interface {name} {}\n", typescript(&schema, 0))
+                    }
+                    _ => format!("export type {name} = {};\n", typescript(&schema, 0)),
+                })
+                .collect();
+            declarations.join("\n")
+        }
+    }
+}
+
+/// TypeScript type accepting the values of `schema`, as produced by `infer`
+fn typescript(schema: &Value, depth: usize) -> String {
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(kind) => vec![kind],
+        Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    if types.is_empty() {
+        return String::from("unknown");
+    }
+    let mut union: Vec<String> = vec![];
+    for kind in types {
+        let member = match kind {
+            "integer" | "number" => String::from("number"),
+            "array" => {
+                let items = typescript(&schema["items"], depth);
+                if items.contains(" | ") {
+                    format!("({items})[]")
+                } else {
+                    format!("{items}[]")
+                }
+            }
+            "object" => object_type(schema, depth),
+            kind => String::from(kind),
+        };
+        if !union.contains(&member) {
+            union.push(member);
+        }
+    }
+    union.join(" | ")
+}
+
+fn object_type(schema: &Value, depth: usize) -> String {
+    let Some(properties) = schema["properties"].as_object().filter(|p| !p.is_empty()) else {
+        return String::from("Record<string, unknown>");
+    };
+    let required: Vec<&str> = schema["required"]
+        .as_array()
+        .map(|required| required.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let indent = "  ".repeat(depth + 1);
+    let mut lines = vec![String::from("{")];
+    for (key, property) in properties {
+        let identifier = key.chars().enumerate().all(|(i, c)| {
+            c == '_' || c == '$' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit())
+        });
+        let name = if identifier && !key.is_empty() {
+            key.clone()
+        } else {
+            Value::String(key.clone()).to_string()
+        };
+        let optional = if required.contains(&key.as_str()) {
+            ""
+        } else {
+            "?"
+        };
+        let kind = typescript(property, depth + 1);
+        lines.push(format!("{indent}{name}{optional}: {kind};"));
+    }
+    lines.push(format!("{}}}", "  ".repeat(depth)));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_missing_from_some_items_are_optional() {
+        let items = [json!({ "id": 1, "title": "a" }), json!({ "id": 2 })];
+        let schema = infer(&items);
+        assert_eq!(schema["type"], "object");
+        assert_eq!(schema["required"], json!(["id"]));
+        assert_eq!(schema["properties"]["title"], json!({ "type": "string" }));
+    }
+
+    #[test]
+    fn fields_seen_with_several_types_accept_each() {
+        let items = [
+            json!({ "value": "a" }),
+            json!({ "value": null }),
+            json!({ "value": 1 }),
+        ];
+        let schema = infer(&items);
+        assert_eq!(
+            schema["properties"]["value"]["type"],
+            json!(["string", "null", "integer"])
+        );
+    }
+
+    #[test]
+    fn integers_widen_to_numbers() {
+        let schema = infer(&[json!(1), json!(1.5)]);
+        assert_eq!(schema, json!({ "type": "number" }));
+    }
+
+    #[test]
+    fn array_items_and_nested_objects_are_inferred() {
+        let items = [
+            json!({ "tags": ["a"], "author": { "name": "x", "age": 3 } }),
+            json!({ "tags": [], "author": { "name": "y" } }),
+        ];
+        let schema = infer(&items);
+        assert_eq!(
+            schema["properties"]["tags"],
+            json!({ "type": "array", "items": { "type": "string" } })
+        );
+        assert_eq!(schema["properties"]["author"]["required"], json!(["name"]));
+    }
+
+    #[test]
+    fn nothing_seen_accepts_anything() {
+        assert_eq!(infer(&[]), json!({}));
+    }
+
+    #[test]
+    fn typescript_marks_optional_fields_and_unions() {
+        let items = [
+            json!({ "id": 1, "tags": ["a", 2], "note": null }),
+            json!({ "id": 2, "tags": [], "note": "x", "first-name": "y" }),
+        ];
+        assert_eq!(
+            typescript(&infer(&items), 0),
+            "{\n  id: number;\n  tags: (string | number)[];\n  note: null | string;\n  \"first-name\"?: string;\n}"
+        );
+        assert_eq!(typescript(&json!({}), 0), "unknown");
+    }
+
+    #[test]
+    fn infer_all_skips_kept_entries_and_needs_no_ids() {
+        let data = json!({
+            "$schema": "./db-schema.json",
+            "posts": [{ "title": "a" }],
+            "profile": { "name": "x" },
+        });
+        let data = data.as_object().expect("object source");
+        let output = infer_all(data, SchemaFormat::Typescript);
+        assert_eq!(
+            output,
+            "export interface Post {\n  title: string;\n}\n\nexport interface Profile {\n  name: string;\n}\n"
+        );
+        let schemas: Value =
+            serde_json::from_str(&infer_all(data, SchemaFormat::JsonSchema)).expect("JSON output");
+        assert_eq!(schemas.as_object().map(Map::len), Some(2));
+    }
+
+    #[test]
+    fn typescript_names_are_identifiers_clear_of_globals() {
+        let data = json!({
+            "blog-posts": [{ "id": 1 }],
+            "blogPost": { "id": 1 },
+            "errors": [{ "id": 1 }],
+            "2fa_codes": [{ "id": 1 }],
+        });
+        let data = data.as_object().expect("object source");
+        let names: Vec<String> = infer_all(data, SchemaFormat::Typescript)
+            .lines()
+            .filter_map(|line| line.strip_prefix("export interface "))
+            .map(|line| line.trim_end_matches(" {").to_string())
+            .collect();
+        assert_eq!(names, vec!["BlogPost", "BlogPost2", "Error2", "_2faCode"]);
+    }
+}
@@ -1,42 +1,583 @@
-use serde_json::Value;
-use std::collections::HashMap;
+use clap::ValueEnum;
+use indexmap::IndexMap;
+use serde::Serialize;
+use serde_json::{ser::PrettyFormatter, Map, Value};
+use std::collections::{hash_map::DefaultHasher, VecDeque};
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::{fs::File, io::Read};
-use tokio::fs::OpenOptions;
+use tokio::fs::{self, OpenOptions};
 use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::{generator, json_text::JsonText, logger, random::Random};
+
+type Serialized = IndexMap<String, Value>;
+
+/// Key of the only file of a single-file source
+const WHOLE_SOURCE: &str = "";
+
+/// Number of file contents remembered to recognise our own changes on disk
+const KNOWN_HISTORY: usize = 64;
+
+/// Whitespace of the source file, reproduced on save so unchanged data stays byte-identical
+#[derive(Debug, Clone)]
+struct Layout {
+    indent: Vec<u8>,
+    trailing_newline: bool,
+}
+
+impl Layout {
+    fn detect(content: &str) -> Self {
+        let indent = content
+            .lines()
+            .skip(1)
+            .find_map(|line| {
+                let width = line.len() - line.trim_start_matches([' ', '\t']).len();
+                (width > 0).then(|| line.as_bytes()[..width].to_vec())
+            })
+            .unwrap_or_else(|| b"  ".to_vec());
+        Self {
+            indent,
+            trailing_newline: content.ends_with('\n'),
+        }
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            indent: b"  ".to_vec(),
+            trailing_newline: false,
+        }
+    }
+}
+
+fn invalid_data(err: impl Display) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// JSON pointer to the first null of `value`, which TOML has no way to write
+fn find_null(value: &Value, path: String) -> Option<String> {
+    match value {
+        Value::Null => Some(path),
+        Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .find_map(|(i, value)| find_null(value, format!("{path}/{i}"))),
+        Value::Object(map) => map
+            .iter()
+            .find_map(|(key, value)| find_null(value, format!("{path}/{key}"))),
+        _ => None,
+    }
+}
+
+/// Syntax of the source file. JSON files keep their layout on save, only the values that
+/// changed are rewritten, while the other formats are written whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Json,
+    /// Read as JSON5, written back as plain JSON
+    Json5,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /// Guesses the format from the file extension
+    fn detect(path: &Path) -> Option<Self> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("json") => Some(Self::Json),
+            Some("json5") => Some(Self::Json5),
+            Some("yaml" | "yml") => Some(Self::Yaml),
+            Some("toml") => Some(Self::Toml),
+            _ => None,
+        }
+    }
+
+    fn parse(self, content: &str) -> std::io::Result<Value> {
+        match self {
+            Self::Json => Ok(serde_json::from_str(content)?),
+            Self::Json5 => json5::from_str(content).map_err(invalid_data),
+            Self::Yaml => serde_yaml::from_str(content).map_err(invalid_data),
+            Self::Toml => toml::from_str(content).map_err(invalid_data),
+        }
+    }
+}
+
+/// When mutations are written back to the source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Persist {
+    /// Write after every mutation
+    Always,
+    /// Keep every change in memory only
+    Never,
+    /// Write once a burst of mutations has settled
+    Debounced,
+}
+
+/// A file backing either the whole database or, for directory sources, a single collection
+#[derive(Debug)]
+struct SourceFile {
+    path: PathBuf,
+    format: Format,
+    layout: Layout,
+    /// Text last loaded from or handed over for this file, reused on save for JSON files
+    text: Option<JsonText>,
+    /// Hash of the data last loaded from or handed over for this file
+    data: u64,
+}
+
+impl SourceFile {
+    /// Content of the file holding `value`, along with its text to reuse on the next save
+    fn format<T: Serialize>(&self, value: &T) -> std::io::Result<(Vec<u8>, Option<JsonText>)> {
+        match self.format {
+            Format::Json => {
+                if let Some(text) = &self.text {
+                    let value = serde_json::to_value(value)?;
+                    let rendered = JsonText::parse(text.render(&value, &self.layout.indent))
+                        .filter(|rendered| *rendered.value() == value);
+                    if let Some(rendered) = rendered {
+                        return Ok((rendered.text().as_bytes().to_vec(), Some(rendered)));
+                    }
+                }
+            }
+            Format::Json5 => {}
+            Format::Yaml => {
+                return serde_yaml::to_string(value)
+                    .map(|content| (content.into_bytes(), None))
+                    .map_err(invalid_data)
+            }
+            Format::Toml => {
+                let value = serde_json::to_value(value)?;
+                if let Some(path) = find_null(&value, String::new()) {
+                    return Err(invalid_data(format!("TOML cannot hold the null at {path}")));
+                }
+                return toml::to_string_pretty(&value)
+                    .map(|content| (content.into_bytes(), None))
+                    .map_err(invalid_data);
+            }
+        }
+        let mut content = vec![];
+        let formatter = PrettyFormatter::with_indent(&self.layout.indent);
+        let mut serializer = serde_json::Serializer::with_formatter(&mut content, formatter);
+        if value.serialize(&mut serializer).is_err() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid data  in database",
+            ));
+        }
+        if self.layout.trailing_newline {
+            content.push(b'\n');
+        }
+        let text = match self.text {
+            Some(_) => String::from_utf8(content.clone())
+                .ok()
+                .and_then(JsonText::parse),
+            None => None,
+        };
+        Ok((content, text))
+    }
+}
 
 #[derive(Debug)]
 pub struct Source {
+    /// A file holding every collection, or a directory holding one file per collection
     pub path: String,
+    format: Option<Format>,
+    indent: Option<usize>,
+    /// Set when the source is a template expanded by the `generator`
+    generator: Option<Random>,
+    /// Files read by the last `process`, keyed by collection for directory sources
+    files: IndexMap<String, SourceFile>,
+    writer: Option<UnboundedSender<(PathBuf, Vec<u8>)>>,
+    writer_task: Option<JoinHandle<()>>,
+    /// Hashes of the file contents recently loaded or handed to the writer, so that
+    /// `reload` ignores changes made by the server itself
+    known: VecDeque<u64>,
 }
 
 impl Source {
-    pub fn new(path: &str) -> Self {
+    /// `format` overrides the one guessed from the extension, `indent` reformats the whole
+    /// file with that indentation width on save instead of keeping its layout, `generator`
+    /// expands the source as a template
+    pub fn new(
+        path: &str,
+        format: Option<Format>,
+        indent: Option<usize>,
+        generator: Option<Random>,
+    ) -> Self {
         Self {
             path: String::from(path),
+            format,
+            indent,
+            generator,
+            files: IndexMap::new(),
+            writer: None,
+            writer_task: None,
+            known: VecDeque::new(),
         }
     }
 
+    pub fn is_directory(&self) -> bool {
+        Path::new(&self.path).is_dir()
+    }
+
+    /// Spawns the task every `write_all` goes through, so writes land on disk in order
+    pub fn start_writer(&mut self) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.writer_task = Some(tokio::spawn(Self::writer(receiver)));
+        self.writer = Some(sender);
+    }
+
+    /// Stops handing writes over, returning the writer task which ends once it has written
+    /// the pending ones
+    pub fn close(&mut self) -> Option<JoinHandle<()>> {
+        self.writer = None;
+        self.writer_task.take()
+    }
+
     pub fn process(&mut self) -> std::io::Result<Value> {
-        let mut file = File::open(&self.path)?;
+        let contents = self.read()?;
+        self.parse(contents)
+    }
+
+    /// Reads the source again, returning `None` when it did not change since
+    /// it was last loaded or written by the server
+    pub fn reload(&mut self) -> std::io::Result<Option<Value>> {
+        let contents = self.read()?;
+        let unchanged = contents.len() == self.files.len()
+            && contents.iter().all(|(name, (_, content))| {
+                self.files.contains_key(name)
+                    && self.known.contains(&Self::hash(content.as_bytes()))
+            });
+        if unchanged {
+            return Ok(None);
+        }
+        self.parse(contents).map(Some)
+    }
+
+    /// Schemas of the collections read from `path`, or from the sidecar file next to the
+    /// source (`db.schema.json` for `db.json` or `db/`), `null` when there is none
+    pub fn read_schemas(&self, path: Option<&str>) -> std::io::Result<Value> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let source = Path::new(&self.path);
+                let Some(stem) = source.file_stem().and_then(|stem| stem.to_str()) else {
+                    return Ok(Value::Null);
+                };
+                let sidecar = source.with_file_name(format!("{stem}.schema.json"));
+                if !sidecar.is_file() {
+                    return Ok(Value::Null);
+                }
+                sidecar
+            }
+        };
+        let format = Format::detect(&path).unwrap_or(Format::Json);
+        format.parse(&Self::read_file(&path)?)
+    }
+
+    /// Content of every backing file, keyed by collection for directory sources
+    fn read(&self) -> std::io::Result<IndexMap<String, (PathBuf, String)>> {
+        let mut contents = IndexMap::new();
+        if !self.is_directory() {
+            let path = PathBuf::from(&self.path);
+            let content = Self::read_file(&path)?;
+            contents.insert(String::from(WHOLE_SOURCE), (path, content));
+            return Ok(contents);
+        }
+        let mut paths = vec![];
+        for entry in std::fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            let hidden = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_none_or(|name| name.starts_with('.'));
+            if path.is_file() && !hidden && Format::detect(&path).is_some() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        for path in paths {
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let name = String::from(name);
+            if contents.contains_key(&name) {
+                return Err(invalid_data(format!(
+                    "duplicate collection {name} in source"
+                )));
+            }
+            let content = Self::read_file(&path)?;
+            contents.insert(name, (path, content));
+        }
+        Ok(contents)
+    }
+
+    fn read_file(path: &Path) -> std::io::Result<String> {
+        let mut file = File::open(path)?;
         let mut content = String::new();
         file.read_to_string(&mut content)?;
-        let source = serde_json::from_str(&content)?;
+        Ok(content)
+    }
+
+    fn parse(&mut self, contents: IndexMap<String, (PathBuf, String)>) -> std::io::Result<Value> {
+        let directory = self.is_directory();
+        let mut files = IndexMap::new();
+        let mut collections = Map::new();
+        let mut source = Value::Null;
+        for (name, (path, content)) in contents {
+            let format = self
+                .format
+                .or_else(|| Format::detect(&path))
+                .unwrap_or(Format::Json);
+            let mut value = format.parse(&content)?;
+            if let Some(random) = &self.generator {
+                value = generator::expand(&value, random)?;
+            }
+            let mut layout = Layout::detect(&content);
+            if let Some(indent) = self.indent {
+                layout.indent = vec![b' '; indent];
+            }
+            self.remember(Self::hash(content.as_bytes()));
+            let data = Self::hash_data(&value)?;
+            let keep_layout =
+                format == Format::Json && self.indent.is_none() && self.generator.is_none();
+            files.insert(
+                name.clone(),
+                SourceFile {
+                    path,
+                    format,
+                    layout,
+                    text: keep_layout.then(|| JsonText::parse(content)).flatten(),
+                    data,
+                },
+            );
+            if directory {
+                collections.insert(name, value);
+            } else {
+                source = value;
+            }
+        }
+        self.files = files;
+        if directory {
+            source = Value::Object(collections);
+        }
         Ok(source)
     }
 
-    pub fn write_all(&self, serialized: HashMap<String, Vec<Value>>) -> std::io::Result<()> {
-        let path = self.path.clone();
-        tokio::spawn(async move {
-            let Ok(res) = serde_json::to_string_pretty(&serialized) else {
-                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid data  in database"));
+    /// Fails like `write_all` would on data that a file of the source cannot hold, without
+    /// writing anything
+    pub fn check(&self, serialized: &Serialized) -> std::io::Result<()> {
+        for (name, file) in &self.files {
+            if file.format != Format::Toml {
+                continue;
+            }
+            let null = if name == WHOLE_SOURCE {
+                serialized
+                    .iter()
+                    .find_map(|(key, value)| find_null(value, format!("/{key}")))
+            } else {
+                serialized
+                    .get(name)
+                    .and_then(|value| find_null(value, String::new()))
             };
-            let mut file = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .open(path)
-                .await?;
-            file.write_all(res.as_bytes()).await
-        });
+            if let Some(path) = null {
+                return Err(invalid_data(format!("TOML cannot hold the null at {path}")));
+            }
+        }
         Ok(())
     }
+
+    /// Hands the files whose content changed over to the writer
+    pub fn write_all(&mut self, serialized: Serialized) -> std::io::Result<()> {
+        let Some(writer) = self.writer.clone() else {
+            return Err(std::io::Error::other("source writer not started"));
+        };
+        let mut changes = vec![];
+        if self.is_directory() {
+            for (name, resource) in &serialized {
+                if !self.files.contains_key(name) {
+                    let path = Path::new(&self.path).join(format!("{name}.json"));
+                    let file = SourceFile {
+                        path,
+                        format: self.format.unwrap_or(Format::Json),
+                        layout: Layout::default(),
+                        text: None,
+                        data: 0,
+                    };
+                    self.files.insert(name.clone(), file);
+                }
+                changes.push((name.clone(), Self::hash_data(resource)?));
+            }
+        } else if self.files.contains_key(WHOLE_SOURCE) {
+            changes.push((String::from(WHOLE_SOURCE), Self::hash_data(&serialized)?));
+        }
+
+        // Format every file before handing any over, so data that cannot be written leaves
+        // the source untouched
+        let mut contents = vec![];
+        for (name, data) in changes {
+            if self.files[&name].data == data {
+                continue;
+            }
+            let content = if self.is_directory() {
+                self.files[&name].format(&serialized[&name])?
+            } else {
+                self.files[&name].format(&serialized)?
+            };
+            contents.push((name, data, content));
+        }
+        for (name, data, (content, text)) in contents {
+            self.remember(Self::hash(&content));
+            let file = &mut self.files[&name];
+            file.data = data;
+            file.text = text;
+            writer
+                .send((file.path.clone(), content))
+                .map_err(|_| std::io::Error::other("source writer stopped"))?;
+        }
+        Ok(())
+    }
+
+    fn remember(&mut self, hash: u64) {
+        if self.known.len() == KNOWN_HISTORY {
+            self.known.pop_front();
+        }
+        self.known.push_back(hash);
+    }
+
+    fn hash_data<T: Serialize>(data: &T) -> std::io::Result<u64> {
+        Ok(Self::hash(&serde_json::to_vec(data)?))
+    }
+
+    fn hash(content: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    async fn writer(mut receiver: UnboundedReceiver<(PathBuf, Vec<u8>)>) {
+        while let Some((path, content)) = receiver.recv().await {
+            let mut pending = IndexMap::from([(path, content)]);
+            while let Ok((path, content)) = receiver.try_recv() {
+                pending.insert(path, content);
+            }
+            for (path, content) in pending {
+                if let Err(e) = Self::write_file(&path, &content).await {
+                    logger::error(&format!("write {}", path.display()), &e);
+                }
+            }
+        }
+    }
+
+    /// Writes to a temporary file next to `path`, syncs it, then renames it over `path`
+    /// so a crash never leaves a truncated source behind
+    async fn write_file(path: &Path, content: &[u8]) -> std::io::Result<()> {
+        let Some(name) = path.file_name() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "source path is not a file",
+            ));
+        };
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(".tmp");
+        let temp = path.with_file_name(temp_name);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)
+            .await?;
+        file.write_all(content).await?;
+        file.sync_all().await?;
+        drop(file);
+        if let Err(e) = fs::rename(&temp, path).await {
+            let _ = fs::remove_file(&temp).await;
+            return Err(e);
+        }
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if let Ok(dir) = fs::File::open(dir).await {
+                let _ = dir.sync_all().await;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Empty directory of its own for the test `name`
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("jsml-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).expect("temporary directory");
+        directory
+    }
+
+    /// Waits for the writer to put every pending write on disk
+    async fn flush(source: &mut Source) {
+        if let Some(writer) = source.close() {
+            writer.await.expect("writer finished");
+        }
+    }
+
+    #[actix_web::test]
+    async fn reload_ignores_the_servers_own_writes() {
+        let directory = directory("reload");
+        let path = directory.join("db.json");
+        std::fs::write(&path, r#"{ "posts": [] }"#).expect("source written");
+        let mut source = Source::new(&path.to_string_lossy(), None, None, None);
+        source.process().expect("valid source");
+        source.start_writer();
+        let data = IndexMap::from([(String::from("posts"), json!([{ "id": 1 }]))]);
+        source.write_all(data).expect("written");
+        flush(&mut source).await;
+        let written: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).expect("readable")).expect("JSON");
+        assert_eq!(written, json!({ "posts": [{ "id": 1 }] }));
+        assert!(source.reload().expect("readable").is_none());
+
+        std::fs::write(&path, r#"{ "posts": [{ "id": 2 }] }"#).expect("edited");
+        let reloaded = source.reload().expect("readable");
+        assert_eq!(reloaded, Some(json!({ "posts": [{ "id": 2 }] })));
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[actix_web::test]
+    async fn write_all_rewrites_only_the_files_that_changed() {
+        let directory = directory("changed");
+        std::fs::write(directory.join("posts.json"), "[]").expect("source written");
+        std::fs::write(directory.join("users.json"), "[]").expect("source written");
+        let mut source = Source::new(&directory.to_string_lossy(), None, None, None);
+        source.process().expect("valid source");
+        source.start_writer();
+        // Edited behind the server's back, so a rewrite would show
+        std::fs::write(directory.join("users.json"), "[ ]").expect("edited");
+        let data = IndexMap::from([
+            (String::from("posts"), json!([{ "id": 1 }])),
+            (String::from("users"), json!([])),
+        ]);
+        source.write_all(data).expect("written");
+        flush(&mut source).await;
+        let read = |name: &str| std::fs::read_to_string(directory.join(name)).expect("readable");
+        let posts: Value = serde_json::from_str(&read("posts.json")).expect("JSON");
+        assert_eq!(posts, json!([{ "id": 1 }]));
+        assert_eq!(read("users.json"), "[ ]");
+        let _ = std::fs::remove_dir_all(directory);
+    }
 }
@@ -1,38 +1,202 @@
-use std::sync::Arc;
-
+use actix_web::web;
+use indexmap::IndexMap;
+use parking_lot::RwLock;
+use rand::rngs::StdRng;
 use serde_json::Value;
+use std::{collections::HashMap, io::ErrorKind, sync::Arc, time::Duration};
+use tokio::sync::{broadcast, Notify};
+use tokio::task::JoinHandle;
 
-use crate::database::Database;
+use crate::database::{Database, Options, Page};
+use crate::events::{Event, EventKind, Events};
 use crate::jsml_error::JsmlError;
-use crate::logger::LogEntry;
+use crate::logger;
 use crate::routes::QueryParams;
-use crate::source::Source;
+use crate::source::{Persist, Source};
 use crate::Args;
 
 pub struct State {
     pub port: usize,
     pub database: Database,
+    /// The database as loaded at startup or by the last reload, brought back by `reset`
+    initial: Database,
+    /// State of the random generator when `initial` was loaded, so a seeded run generates
+    /// the same ids again after a reset
+    initial_random: StdRng,
     // entries: Vec<Arc<dyn LogEntry>>,
     source: Source,
+    persist: Persist,
+    /// Quiet time ending a burst of mutations under `Persist::Debounced`
+    persist_delay: Duration,
+    /// Woken on every mutation left unwritten by `persist`
+    changes: Arc<Notify>,
+    /// Set while the database holds mutations not handed over to the source yet
+    dirty: bool,
+    /// In-memory copies of the database and of the random generator taken through
+    /// `/__admin/snapshot`
+    snapshots: HashMap<String, (Database, StdRng)>,
+    events: Events,
 }
 
 impl State {
     pub fn new(args: &Args) -> Result<Self, JsmlError> {
-        let mut source = Source::new(&args.source);
-        let database = Database::new(&args.id, &source.process()?)?;
+        let mut options = Options::from(args);
+        let generator = args.generate.then(|| options.random.clone());
+        let mut source = Source::new(args.source(), args.format, args.indent, generator);
+        options.schemas = source.read_schemas(args.schema.as_deref())?;
+        let database = Database::new(&options, &source.process()?)?;
+        // Never write generated data over its template
+        let persist = if args.generate {
+            Persist::Never
+        } else {
+            args.persist
+        };
+        if persist != Persist::Never {
+            source.start_writer();
+        }
         Ok(Self {
             port: args.port,
+            initial: database.clone(),
+            initial_random: options.random.save(),
             database,
             source,
+            persist,
+            persist_delay: Duration::from_millis(args.persist_delay),
+            changes: Arc::new(Notify::new()),
+            dirty: false,
+            snapshots: HashMap::new(),
+            events: Events::new(),
             // entries: vec![],
         })
     }
 
-    pub fn query<'a>(
-        &'a self,
+    /// Writes the database back to its source, as configured by `--persist`. Debounced
+    /// writes are left to `debounce`, once the data is known to fit the source.
+    fn persist(&mut self) -> Result<(), JsmlError> {
+        match self.persist {
+            Persist::Never => Ok(()),
+            Persist::Always => self.write(),
+            Persist::Debounced => {
+                self.source
+                    .check(&self.database.serialize_all())
+                    .map_err(Self::write_error)?;
+                self.dirty = true;
+                self.changes.notify_one();
+                Ok(())
+            }
+        }
+    }
+
+    fn write(&mut self) -> Result<(), JsmlError> {
+        self.source
+            .write_all(self.database.serialize_all())
+            .map_err(Self::write_error)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn write_error(e: std::io::Error) -> JsmlError {
+        match e.kind() {
+            // Data the source format cannot hold, such as null in TOML
+            ErrorKind::InvalidData => JsmlError::bad_request(&e.to_string()),
+            _ => JsmlError::from(e),
+        }
+    }
+
+    /// Writes the mutations left pending by a debounced `persist`
+    pub fn flush(&mut self) -> Result<(), JsmlError> {
+        if self.dirty {
+            self.write()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the pending mutations and stops writing, returning the writer task to wait
+    /// on for them to land on disk
+    pub fn close(&mut self) -> Option<JoinHandle<()>> {
+        if let Err(e) = self.flush() {
+            logger::error(&self.source.path, &e);
+        }
+        self.source.close()
+    }
+
+    /// Applies `change` to `route` and persists it, putting `route` back as it was when
+    /// the change cannot be written to the source
+    fn commit<T>(
+        &mut self,
         route: &str,
-        query: &QueryParams,
-    ) -> Result<Vec<&'a Value>, JsmlError> {
+        change: impl FnOnce(&mut Database) -> Result<T, JsmlError>,
+    ) -> Result<T, JsmlError> {
+        let snapshot = match self.persist {
+            Persist::Never => None,
+            _ => self.database.database.get(route).cloned(),
+        };
+        let result = change(&mut self.database)?;
+        if let Err(e) = self.persist() {
+            if let Some(snapshot) = snapshot {
+                self.database.database.insert(route.to_string(), snapshot);
+            }
+            return Err(e);
+        }
+        Ok(result)
+    }
+
+    /// Swaps in the database currently on disk if it changed, keeping the current one when invalid
+    pub fn reload(&mut self) -> Result<bool, JsmlError> {
+        let Some(data) = self.source.reload()? else {
+            return Ok(false);
+        };
+        self.database = Database::new(&self.database.options, &data)?;
+        self.initial = self.database.clone();
+        self.initial_random = self.database.options.random.save();
+        Ok(true)
+    }
+
+    /// Publishes the change of `item` to the event feed
+    fn changed(&mut self, kind: EventKind, route: &str, item: &Value) {
+        let id = self.database.item_id(item);
+        self.events.publish(kind, route, id, Some(item.clone()));
+    }
+
+    pub fn subscribe(
+        &self,
+        last_id: Option<u64>,
+    ) -> (Vec<Arc<Event>>, broadcast::Receiver<Arc<Event>>) {
+        self.events.subscribe(last_id)
+    }
+
+    /// Drops every change made since the source was loaded, writing the loaded data back.
+    /// The write supersedes any pending one, so it is the one left on disk.
+    pub fn reset(&mut self) -> Result<(), JsmlError> {
+        self.database = self.initial.clone();
+        self.database
+            .options
+            .random
+            .restore(self.initial_random.clone());
+        self.persist()
+    }
+
+    pub fn snapshot(&mut self, name: &str) {
+        let random = self.database.options.random.save();
+        self.snapshots
+            .insert(name.to_string(), (self.database.clone(), random));
+    }
+
+    pub fn restore(&mut self, name: &str) -> Result<(), JsmlError> {
+        let Some(snapshot) = self.snapshots.get(name) else {
+            return Err(JsmlError::not_found(&format!("snapshot {name} not found")));
+        };
+        let (database, random) = snapshot.clone();
+        self.database = database;
+        self.database.options.random.restore(random);
+        self.persist()
+    }
+
+    pub fn serialize_all(&self) -> IndexMap<String, Value> {
+        self.database.serialize_all()
+    }
+
+    pub fn query<'a>(&'a self, route: &str, query: &QueryParams) -> Result<Page<'a>, JsmlError> {
         self.database.query(route, query)
     }
 
@@ -40,71 +204,182 @@ impl State {
         self.database.get(route, id)
     }
 
-    pub fn delete(&mut self, route: &str, id: &str, flush: bool) -> Result<(), JsmlError> {
-        let result = self.database.delete(route, id);
-        match result {
-            Ok(_) => {
-                if flush {
-                    self.source.write_all(self.database.serialize_all())?;
-                }
-                Ok(())
-            }
-            Err(e) => Err(e),
+    pub fn item_id(&self, item: &Value) -> Option<String> {
+        self.database.item_id(item)
+    }
+
+    pub fn get_singular(&self, route: &str) -> Result<&Value, JsmlError> {
+        self.database.get_singular(route)
+    }
+
+    pub fn query_children<'a>(
+        &'a self,
+        parent: &str,
+        id: &str,
+        child: &str,
+        query: &mut QueryParams,
+    ) -> Result<Page<'a>, JsmlError> {
+        self.database.query_children(parent, id, child, query)
+    }
+
+    pub fn embed(&self, route: &str, item: &Value, query: &QueryParams) -> Value {
+        self.database.embed(route, item, query)
+    }
+
+    pub fn delete(&mut self, route: &str, id: &str) -> Result<(), JsmlError> {
+        self.commit(route, |database| database.delete(route, id))?;
+        self.events
+            .publish(EventKind::Deleted, route, Some(id.to_string()), None);
+        Ok(())
+    }
+
+    pub fn put(&mut self, route: &str, id: &str, body: &Value) -> Result<Value, JsmlError> {
+        let result = self.commit(route, |database| database.put(route, id, body))?;
+        self.changed(EventKind::Updated, route, &result);
+        Ok(result)
+    }
+
+    pub fn patch(&mut self, route: &str, id: &str, body: &Value) -> Result<Value, JsmlError> {
+        let result = self.commit(route, |database| database.patch(route, id, body))?;
+        self.changed(EventKind::Updated, route, &result);
+        Ok(result)
+    }
+
+    pub fn post(&mut self, route: &str, body: &Value) -> Result<Value, JsmlError> {
+        let result = self.commit(route, |database| database.post(route, body))?;
+        self.changed(EventKind::Created, route, &result);
+        Ok(result)
+    }
+
+    pub fn post_many(&mut self, route: &str, bodies: &[Value]) -> Result<Vec<Value>, JsmlError> {
+        let result = self.commit(route, |database| database.post_many(route, bodies))?;
+        for item in &result {
+            self.changed(EventKind::Created, route, item);
         }
+        Ok(result)
     }
 
-    pub fn put(
+    pub fn patch_many(
         &mut self,
         route: &str,
-        id: &str,
+        query: &QueryParams,
         body: &Value,
-        flush: bool,
-    ) -> Result<Value, JsmlError> {
-        let result = self.database.put(route, id, body);
-        match result {
-            Ok(res) => {
-                if flush {
-                    self.source.write_all(self.database.serialize_all())?;
-                }
-                Ok(res)
-            }
-            Err(e) => Err(e),
+    ) -> Result<Vec<Value>, JsmlError> {
+        let result = self.commit(route, |database| database.patch_many(route, query, body))?;
+        for item in &result {
+            self.changed(EventKind::Updated, route, item);
         }
+        Ok(result)
     }
 
-    pub fn patch(
+    pub fn delete_many(
         &mut self,
         route: &str,
+        query: &QueryParams,
+    ) -> Result<Vec<String>, JsmlError> {
+        let result = self.commit(route, |database| database.delete_many(route, query))?;
+        for id in &result {
+            self.events
+                .publish(EventKind::Deleted, route, Some(id.clone()), None);
+        }
+        Ok(result)
+    }
+
+    pub fn post_child(
+        &mut self,
+        parent: &str,
         id: &str,
+        child: &str,
         body: &Value,
-        flush: bool,
     ) -> Result<Value, JsmlError> {
-        let result = self.database.patch(route, id, body);
-        match result {
-            Ok(res) => {
-                if flush {
-                    self.source.write_all(self.database.serialize_all())?;
-                }
-                Ok(res)
-            }
-            Err(e) => Err(e),
-        }
+        let result = self.commit(child, |database| {
+            database.post_child(parent, id, child, body)
+        })?;
+        self.changed(EventKind::Created, child, &result);
+        Ok(result)
     }
 
-    pub fn post(&mut self, route: &str, body: &Value, flush: bool) -> Result<Value, JsmlError> {
-        let result = self.database.post(route, body);
-        match result {
-            Ok(res) => {
-                if flush {
-                    self.source.write_all(self.database.serialize_all())?;
-                }
-                Ok(res)
-            }
-            Err(e) => Err(e),
-        }
+    pub fn put_singular(&mut self, route: &str, body: &Value) -> Result<Value, JsmlError> {
+        let result = self.commit(route, |database| database.put_singular(route, body))?;
+        self.events
+            .publish(EventKind::Updated, route, None, Some(result.clone()));
+        Ok(result)
+    }
+
+    pub fn patch_singular(&mut self, route: &str, body: &Value) -> Result<Value, JsmlError> {
+        let result = self.commit(route, |database| database.patch_singular(route, body))?;
+        self.events
+            .publish(EventKind::Updated, route, None, Some(result.clone()));
+        Ok(result)
     }
 
     // pub fn log(&mut self, entry: Arc<dyn LogEntry>) {
     //     self.entries.push(entry);
     // }
 }
+
+/// Flushes `state` once a burst of mutations has been quiet for `--persist-delay`, so a
+/// burst ends up as a single write. Does nothing unless persistence is debounced.
+pub fn debounce(state: web::Data<RwLock<State>>) {
+    let (changes, delay) = {
+        let state = state.read();
+        if state.persist != Persist::Debounced {
+            return;
+        }
+        (state.changes.clone(), state.persist_delay)
+    };
+    tokio::spawn(async move {
+        loop {
+            changes.notified().await;
+            while tokio::time::timeout(delay, changes.notified())
+                .await
+                .is_ok()
+            {}
+            let mut state = state.write();
+            if let Err(e) = state.flush() {
+                logger::error(&state.source.path, &e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::json;
+
+    use super::*;
+    use crate::jsml_error::ErrorKind;
+
+    /// State serving a fresh copy of a TOML source, persisted as `persist` says
+    fn state(name: &str, persist: &str) -> State {
+        let directory = std::env::temp_dir().join(format!("jsml-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&directory).expect("temporary directory");
+        let path = directory.join("db.toml");
+        std::fs::write(&path, "[[posts]]\nid = 1\ntitle = \"a\"\n").expect("source written");
+        let path = path.to_string_lossy();
+        let args = Args::parse_from(["jsml-server", &path, "--persist", persist]);
+        State::new(&args).expect("valid source")
+    }
+
+    #[actix_web::test]
+    async fn changes_the_source_cannot_hold_are_undone() {
+        for persist in ["always", "debounced"] {
+            let mut state = state(&format!("undone-{persist}"), persist);
+            let err = state
+                .patch("posts", "1", &json!({ "title": null }))
+                .expect_err("TOML has no null");
+            assert_eq!(err.kind, ErrorKind::BadRequest, "{persist}");
+            let item = state.get("posts", "1").expect("kept item");
+            assert_eq!(item["title"], json!("a"), "{persist}");
+            let err = state
+                .post("posts", &json!({ "id": 2, "title": null }))
+                .expect_err("TOML has no null");
+            assert_eq!(err.kind, ErrorKind::BadRequest, "{persist}");
+            assert!(state.get("posts", "2").is_err(), "{persist}");
+            if let Some(directory) = std::path::Path::new(&state.source.path).parent() {
+                let _ = std::fs::remove_dir_all(directory);
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use indexmap::IndexMap;
+use jsonschema::JSONSchema;
+use serde_json::Value;
+
+use crate::jsml_error::{JsmlError, Violation};
+
+/// Top-level entry of the source holding the JSON Schema of each collection
+pub const SCHEMA_KEY: &str = "__schemas";
+
+/// Top-level entry pointing editors at a meta-schema describing the whole file
+pub const META_SCHEMA_KEY: &str = "$schema";
+
+#[derive(Debug, Clone)]
+struct Validator {
+    schema: Value,
+    compiled: Arc<JSONSchema>,
+}
+
+/// JSON Schemas the items of each collection, or each singular resource, must satisfy
+#[derive(Debug, Clone, Default)]
+pub struct Schemas {
+    validators: IndexMap<String, Validator>,
+}
+
+impl Schemas {
+    /// Compiles the schemas of a sidecar file, then the ones of the `__schemas` entry of
+    /// the source, which win when both describe a collection
+    pub fn new(sidecar: &Value, section: Option<&Value>) -> Result<Self, JsmlError> {
+        let mut validators = IndexMap::new();
+        for schemas in [Some(sidecar), section]
+            .into_iter()
+            .flatten()
+            .filter(|schemas| !schemas.is_null())
+        {
+            let Some(schemas) = schemas.as_object() else {
+                return Err(JsmlError::bad_request(
+                    "schemas must be an object mapping collection names to JSON Schemas",
+                ));
+            };
+            for (route, schema) in schemas {
+                let compiled = JSONSchema::compile(schema).map_err(|e| {
+                    JsmlError::bad_request(&format!("invalid schema for {route}: {e}"))
+                })?;
+                let validator = Validator {
+                    schema: schema.clone(),
+                    compiled: Arc::new(compiled),
+                };
+                validators.insert(route.clone(), validator);
+            }
+        }
+        Ok(Self { validators })
+    }
+
+    /// The schema declared for `route`, if any
+    pub fn get(&self, route: &str) -> Option<&Value> {
+        self.validators
+            .get(route)
+            .map(|validator| &validator.schema)
+    }
+
+    /// Rejects `value` with the list of its violations when it does not match the schema of `route`
+    pub fn check(&self, route: &str, value: &Value) -> Result<(), JsmlError> {
+        let violations = self.violations(route, value, "");
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(JsmlError::unprocessable(
+            &format!("document does not match the schema of {route}"),
+            violations,
+        ))
+    }
+
+    /// Violations of the schema of `route` by `value`, their paths prefixed with `prefix`
+    pub fn violations(&self, route: &str, value: &Value, prefix: &str) -> Vec<Violation> {
+        let Some(validator) = self.validators.get(route) else {
+            return vec![];
+        };
+        let Err(errors) = validator.compiled.validate(value) else {
+            return vec![];
+        };
+        errors
+            .map(|error| Violation {
+                path: format!("{prefix}{}", error.instance_path),
+                message: error.to_string(),
+            })
+            .collect()
+    }
+}
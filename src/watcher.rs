@@ -0,0 +1,55 @@
+use std::path::{Path, PathBuf};
+
+use actix_web::web;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use parking_lot::RwLock;
+
+use crate::{logger, state::State};
+
+/// Reloads `state` whenever the source file, or a file of the source directory, changes
+/// on disk. The returned watcher stops watching when dropped.
+pub fn watch(path: &str, state: web::Data<RwLock<State>>) -> notify::Result<RecommendedWatcher> {
+    let path = PathBuf::from(path);
+    // Saves usually replace files, so watch the directory holding them rather than the files
+    let (directory, file_name) = if path.is_dir() {
+        (path.clone(), None)
+    } else {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => (
+                parent.to_path_buf(),
+                path.file_name().map(ToOwned::to_owned),
+            ),
+            _ => (PathBuf::from("."), path.file_name().map(ToOwned::to_owned)),
+        }
+    };
+    let source = path.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => return logger::error("watch", &e),
+        };
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            return;
+        }
+        let touches_source = event.paths.iter().any(|path| match &file_name {
+            Some(file_name) => path.file_name() == Some(file_name.as_os_str()),
+            None => path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| !name.starts_with('.')),
+        });
+        if touches_source {
+            reload(&source, &state);
+        }
+    })?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+fn reload(path: &Path, state: &web::Data<RwLock<State>>) {
+    match state.write().reload() {
+        Ok(true) => logger::info("reload", &path.display()),
+        Ok(false) => {}
+        Err(e) => logger::error(&format!("reload {}", path.display()), &e),
+    }
+}
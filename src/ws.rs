@@ -0,0 +1,262 @@
+use std::{collections::HashMap, pin::pin, sync::Arc};
+
+use actix_web::{get, http::StatusCode, rt, web, HttpRequest, HttpResponse};
+use actix_ws::{Message, ProtocolError, Session};
+use futures_util::{future::ready, stream, StreamExt};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    database::Database,
+    events::Event,
+    jsml_error::JsmlError,
+    logger::{LogEntry, RouteEntry},
+    routes::QueryParams,
+    state::State,
+};
+
+/// A message sent by a client, answered by an `ack` or an `error` carrying the same `ref`
+#[derive(Deserialize)]
+struct Request {
+    #[serde(rename = "ref")]
+    reference: Option<Value>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Command {
+    /// Receive the changes of a collection, or of one of its items, passing `filter`
+    Subscribe {
+        collection: String,
+        id: Option<String>,
+        #[serde(default)]
+        filter: HashMap<String, Value>,
+    },
+    Unsubscribe {
+        subscription: u64,
+    },
+    Create {
+        collection: String,
+        document: Value,
+    },
+    /// Merges `document` into the item, or replaces it with `replace`. Without an id,
+    /// `collection` names a singular resource.
+    Update {
+        collection: String,
+        id: Option<String>,
+        document: Value,
+        #[serde(default)]
+        replace: bool,
+    },
+    Delete {
+        collection: String,
+        id: String,
+    },
+}
+
+struct Subscription {
+    id: u64,
+    collection: String,
+    item_id: Option<String>,
+    query: QueryParams,
+}
+
+impl Subscription {
+    fn matches(&self, event: &Event) -> bool {
+        if event.collection != self.collection {
+            return false;
+        }
+        if self.item_id.is_some() && event.item_id != self.item_id {
+            return false;
+        }
+        // Deleted items are gone, so their deletion reaches every subscriber of their id
+        event
+            .document
+            .as_ref()
+            .is_none_or(|document| Database::match_query(&self.query, document))
+    }
+}
+
+enum Input {
+    Client(Result<Message, ProtocolError>),
+    Change(Arc<Event>),
+    Lagged(u64),
+    Closed,
+}
+
+#[get("/ws")]
+async fn socket(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<RwLock<State>>,
+) -> actix_web::Result<HttpResponse> {
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    let receiver = {
+        let data = data.read();
+        let mut log = RouteEntry::new(&format!("GET - localhost:{}/ws", data.port));
+        log.update(StatusCode::SWITCHING_PROTOCOLS);
+        data.subscribe(None).1
+    };
+    let changes = stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(event) => Some((Input::Change(event), receiver)),
+            Err(RecvError::Lagged(missed)) => Some((Input::Lagged(missed), receiver)),
+            Err(RecvError::Closed) => None,
+        }
+    });
+    let messages = messages
+        .map(Input::Client)
+        .chain(stream::once(ready(Input::Closed)));
+    rt::spawn(serve(session, stream::select(messages, changes), data));
+    Ok(response)
+}
+
+/// Answers the commands of a client and pushes it the changes it subscribed to
+async fn serve(
+    mut session: Session,
+    inputs: impl stream::Stream<Item = Input>,
+    data: web::Data<RwLock<State>>,
+) {
+    let mut inputs = pin!(inputs);
+    let mut subscriptions: Vec<Subscription> = vec![];
+    let mut next_id = 1;
+    while let Some(input) = inputs.next().await {
+        let reply = match input {
+            Input::Client(Ok(Message::Text(text))) => {
+                answer(&text, &mut subscriptions, &mut next_id, &data)
+            }
+            Input::Client(Ok(Message::Ping(bytes))) => {
+                if session.pong(&bytes).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            Input::Client(Ok(Message::Close(reason))) => {
+                let _ = session.close(reason).await;
+                return;
+            }
+            Input::Client(Ok(_)) => continue,
+            Input::Client(Err(_)) | Input::Closed => break,
+            Input::Change(event) => {
+                let matched: Vec<u64> = subscriptions
+                    .iter()
+                    .filter(|subscription| subscription.matches(&event))
+                    .map(|subscription| subscription.id)
+                    .collect();
+                if matched.is_empty() {
+                    continue;
+                }
+                json!({ "type": "change", "subscriptions": matched, "event": event.to_json() })
+            }
+            Input::Lagged(missed) => json!({
+                "type": "error",
+                "error": {
+                    "code": "lagged",
+                    "message": format!("{missed} changes were dropped, refetch the collections"),
+                },
+            }),
+        };
+        if session.text(reply.to_string()).await.is_err() {
+            break;
+        }
+    }
+    let _ = session.close(None).await;
+}
+
+/// Runs a command sent by the client, replying with its outcome
+fn answer(
+    text: &str,
+    subscriptions: &mut Vec<Subscription>,
+    next_id: &mut u64,
+    data: &RwLock<State>,
+) -> Value {
+    let request = match serde_json::from_str::<Request>(text) {
+        Ok(request) => request,
+        Err(e) => return respond(None, Err(JsmlError::bad_request(&e.to_string()))),
+    };
+    let result = match request.command {
+        Command::Subscribe {
+            collection,
+            id,
+            filter,
+        } => {
+            // Filters are query parameters, so `{"views_gte": 10}` reads as `views_gte=10`
+            let filter = filter
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Value::String(value) => (key, value),
+                    value => (key, value.to_string()),
+                })
+                .collect();
+            subscriptions.push(Subscription {
+                id: *next_id,
+                collection,
+                item_id: id,
+                query: QueryParams::new(filter),
+            });
+            *next_id += 1;
+            Ok(json!({ "subscription": *next_id - 1 }))
+        }
+        Command::Unsubscribe { subscription } => {
+            subscriptions.retain(|s| s.id != subscription);
+            Ok(json!({ "subscription": subscription }))
+        }
+        command => execute(data, command),
+    };
+    respond(request.reference, result)
+}
+
+fn execute(data: &RwLock<State>, command: Command) -> Result<Value, JsmlError> {
+    let mut data = data.write();
+    match command {
+        Command::Create {
+            collection,
+            document,
+        } => data.post(&collection, &document),
+        Command::Update {
+            collection,
+            id: Some(id),
+            document,
+            replace,
+        } => {
+            if replace {
+                data.put(&collection, &id, &document)
+            } else {
+                data.patch(&collection, &id, &document)
+            }
+        }
+        Command::Update {
+            collection,
+            id: None,
+            document,
+            replace,
+        } => {
+            if replace {
+                data.put_singular(&collection, &document)
+            } else {
+                data.patch_singular(&collection, &document)
+            }
+        }
+        Command::Delete { collection, id } => {
+            data.delete(&collection, &id).map(|()| json!({ "id": id }))
+        }
+        Command::Subscribe { .. } | Command::Unsubscribe { .. } => {
+            Err(JsmlError::internal("not a database command"))
+        }
+    }
+}
+
+fn respond(reference: Option<Value>, result: Result<Value, JsmlError>) -> Value {
+    match result {
+        Ok(result) => json!({ "type": "ack", "ref": reference, "result": result }),
+        Err(e) => json!({
+            "type": "error",
+            "ref": reference,
+            "error": { "code": e.kind.code(), "message": e.details },
+        }),
+    }
+}